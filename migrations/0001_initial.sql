@@ -0,0 +1,24 @@
+-- Initial schema.
+-- Everything is created IF NOT EXISTS, so databases that were set up by hand
+-- before migrations existed are adopted as they are.
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE TABLE IF NOT EXISTS dmslite.main_table (
+    id SERIAL PRIMARY KEY,
+    upload_date DATE,
+    filepath VARCHAR(255),
+    title TEXT
+);
+
+CREATE TABLE IF NOT EXISTS dmslite.document_content (
+    id SERIAL PRIMARY KEY,
+    content TEXT,
+    summary TEXT,
+    buzzwords TEXT,
+    FOREIGN KEY (id) REFERENCES dmslite.main_table(id) ON DELETE CASCADE
+);
+
+-- search indices
+CREATE INDEX IF NOT EXISTS idx_content_trgm ON dmslite.document_content USING gin (content gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS idx_summary_trgm ON dmslite.document_content USING gin (summary gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS idx_buzzwords_trgm ON dmslite.document_content USING gin (buzzwords gin_trgm_ops);
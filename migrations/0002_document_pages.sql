@@ -0,0 +1,9 @@
+-- OCR text of every single page, to find the page a search hit is on
+CREATE TABLE IF NOT EXISTS dmslite.document_pages (
+    id INTEGER REFERENCES dmslite.main_table(id) ON DELETE CASCADE,
+    page INTEGER,
+    content TEXT,
+    PRIMARY KEY (id, page)
+);
+
+CREATE INDEX IF NOT EXISTS idx_pages_trgm ON dmslite.document_pages USING gin (content gin_trgm_ops);
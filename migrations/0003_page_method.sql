@@ -0,0 +1,4 @@
+-- How the text of a page was obtained: 'text_layer' (embedded PDF text) or 'ocr'.
+-- All pages stored before were OCRed.
+ALTER TABLE dmslite.document_pages ADD COLUMN IF NOT EXISTS method TEXT;
+UPDATE dmslite.document_pages SET method = 'ocr' WHERE method IS NULL;
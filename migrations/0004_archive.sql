@@ -0,0 +1,3 @@
+-- Searchable PDF created from the OCR result.
+-- filepath is NULL if the original was replaced by it, archive_filepath is NULL if none was created.
+ALTER TABLE dmslite.main_table ADD COLUMN IF NOT EXISTS archive_filepath TEXT;
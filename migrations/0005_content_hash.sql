@@ -0,0 +1,4 @@
+-- SHA-256 of the consumed file, used to detect exact duplicates.
+-- NULL for documents consumed before. Not unique, linked duplicates share it.
+ALTER TABLE dmslite.main_table ADD COLUMN IF NOT EXISTS content_hash TEXT;
+CREATE INDEX IF NOT EXISTS idx_main_content_hash ON dmslite.main_table (content_hash);
@@ -0,0 +1,15 @@
+-- Tags of the documents. Tags created from the LLM buzzwords are only suggested
+-- until a user adds them to the document.
+CREATE TABLE IF NOT EXISTS dmslite.tags (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS dmslite.document_tags (
+    id INTEGER NOT NULL REFERENCES dmslite.main_table(id) ON DELETE CASCADE,
+    tag_id INTEGER NOT NULL REFERENCES dmslite.tags(id) ON DELETE CASCADE,
+    suggested BOOLEAN NOT NULL DEFAULT false,
+    PRIMARY KEY (id, tag_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_document_tags_tag ON dmslite.document_tags (tag_id);
@@ -0,0 +1,10 @@
+-- Senders of the documents. A proposed correspondent is only confirmed once a user sets it.
+CREATE TABLE IF NOT EXISTS dmslite.correspondents (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL
+);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_correspondents_name ON dmslite.correspondents (lower(name));
+
+ALTER TABLE dmslite.main_table ADD COLUMN IF NOT EXISTS correspondent_id INTEGER REFERENCES dmslite.correspondents(id) ON DELETE SET NULL;
+ALTER TABLE dmslite.main_table ADD COLUMN IF NOT EXISTS correspondent_confirmed BOOLEAN NOT NULL DEFAULT false;
+CREATE INDEX IF NOT EXISTS idx_main_correspondent ON dmslite.main_table (correspondent_id);
@@ -0,0 +1,3 @@
+-- Date of the document itself, found in its text. NULL if none was found.
+ALTER TABLE dmslite.main_table ADD COLUMN IF NOT EXISTS document_date DATE;
+CREATE INDEX IF NOT EXISTS idx_main_document_date ON dmslite.main_table (document_date);
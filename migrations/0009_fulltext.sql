@@ -0,0 +1,10 @@
+-- Full-text search over buzzwords, summary and content, weighted in this order.
+-- The text search configuration (e.g. german) is chosen from tesseract_lang when a document is consumed,
+-- documents consumed before use 'simple' (no stemming).
+ALTER TABLE dmslite.document_content ADD COLUMN IF NOT EXISTS fts_config regconfig NOT NULL DEFAULT 'simple';
+ALTER TABLE dmslite.document_content ADD COLUMN IF NOT EXISTS search_vector tsvector GENERATED ALWAYS AS (
+    setweight(to_tsvector(fts_config, coalesce(buzzwords, '')), 'A') ||
+    setweight(to_tsvector(fts_config, coalesce(summary, '')), 'B') ||
+    setweight(to_tsvector(fts_config, coalesce(content, '')), 'C')
+) STORED;
+CREATE INDEX IF NOT EXISTS idx_content_fts ON dmslite.document_content USING gin (search_vector);
@@ -0,0 +1,22 @@
+-- Embedding vectors of overlapping chunks of the page texts for the semantic search.
+-- pgvector is not required: the vectors are stored as real[] and compared by cosine_similarity.
+CREATE TABLE IF NOT EXISTS dmslite.document_chunks (
+    id INTEGER NOT NULL REFERENCES dmslite.main_table(id) ON DELETE CASCADE,
+    chunk INTEGER NOT NULL,
+    page INTEGER NOT NULL,
+    content TEXT NOT NULL,
+    model TEXT NOT NULL,
+    embedding REAL[] NOT NULL,
+    PRIMARY KEY (id, chunk)
+);
+
+CREATE INDEX IF NOT EXISTS idx_document_chunks_model ON dmslite.document_chunks (model);
+
+CREATE OR REPLACE FUNCTION dmslite.cosine_similarity(a REAL[], b REAL[]) RETURNS REAL
+LANGUAGE sql IMMUTABLE STRICT PARALLEL SAFE AS $$
+    SELECT CASE WHEN norm_a = 0 OR norm_b = 0 THEN 0 ELSE dot / (norm_a * norm_b) END::real
+    FROM (
+        SELECT sum(x * y) AS dot, sqrt(sum(x * x)) AS norm_a, sqrt(sum(y * y)) AS norm_b
+        FROM unnest(a, b) AS v(x, y)
+    ) AS sums
+$$;
@@ -0,0 +1,10 @@
+-- Kind of the document and the amounts of money in it, both extracted by the LLM.
+ALTER TABLE dmslite.main_table ADD COLUMN IF NOT EXISTS document_type TEXT;
+
+CREATE TABLE IF NOT EXISTS dmslite.document_amounts (
+    id INTEGER NOT NULL REFERENCES dmslite.main_table(id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    value DOUBLE PRECISION NOT NULL,
+    currency TEXT NOT NULL,
+    PRIMARY KEY (id, position)
+);
@@ -0,0 +1,6 @@
+-- Embedding model the chunks of a document were computed with, also set for documents without any chunk
+-- (e.g. empty pages), so that `dmslite embed` does not try them again and again.
+ALTER TABLE dmslite.main_table ADD COLUMN IF NOT EXISTS embedding_model TEXT;
+
+UPDATE dmslite.main_table m SET embedding_model = (SELECT min(ch.model) FROM dmslite.document_chunks ch WHERE ch.id = m.id)
+WHERE m.embedding_model IS NULL;
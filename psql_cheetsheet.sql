@@ -2,44 +2,28 @@
 -- ## As super User
 
 	- CREATE DATABASE dmslite OWNER dmslite;
-	- CREATE SCHEMA dmslite;
 
 -- ## as dmslite user
 
--- ### create search Index
-CREATE EXTENSION pg_trgm;
-
-CREATE INDEX idx_content_trgm ON document_content USING gin (content gin_trgm_ops);
-CREATE INDEX idx_summary_trgm ON document_content USING gin (summary gin_trgm_ops);
-CREATE INDEX idx_buzzwords_trgm ON document_content USING gin (buzzwords gin_trgm_ops);
-
--- ### create tables
-
-CREATE TABLE dmslite.main_table (
-    id SERIAL PRIMARY KEY,
-    upload_date DATE,
-    filepath VARCHAR(255),
-    title TEXT
-);
-
-
-CREATE TABLE dmslite.document_content (
-    id SERIAL PRIMARY KEY,
-    -- Other columns in table2
-    content TEXT,
-    summary TEXT,
-    buzzwords TEXT,
-    -- Add more columns as needed
-    FOREIGN KEY (id) REFERENCES main_table(id) ON DELETE CASCADE
-);
+-- ### create schema, tables and search indices
+-- The schema is created by `dmslite db init` from the migrations in migrations/.
 
 -- ### clean up
 
+DROP TABLE schema_migrations;
+DROP TABLE document_tags;
+DROP TABLE document_chunks;
+DROP TABLE document_amounts;
+DROP TABLE tags;
+DROP TABLE document_pages;
 DROP TABLE document_content;
 DROP TABLE main_table;
+DROP TABLE correspondents;
+DROP FUNCTION cosine_similarity;
 DROP INDEX IF EXISTS idx_content_trgm;
 DROP INDEX IF EXISTS idx_summary_trgm;
 DROP INDEX IF EXISTS idx_buzzwords_trgm;
+DROP INDEX IF EXISTS idx_pages_trgm;
 DROP FUNCTION fuzzy_search_document_content;
 
 
@@ -81,3 +65,12 @@ JOIN main_table ON subquery.id = main_table.id
 ORDER BY subquery.distance;
 
 
+
+-- Full-text search in the language of tesseract_lang. A constant configuration (not c.fts_config) lets Postgres use idx_content_fts.
+SELECT m.id, m.title, ts_rank(c.search_vector, websearch_to_tsquery('german', 'Searchphrase'), 32) AS rank
+FROM main_table m JOIN document_content c ON c.id = m.id
+WHERE c.search_vector @@ websearch_to_tsquery('german', 'Searchphrase')
+ORDER BY rank DESC;
+
+-- Stem documents consumed before the full-text search in the language of tesseract_lang
+UPDATE document_content SET fts_config = 'german' WHERE fts_config = 'simple'::regconfig;
@@ -0,0 +1,248 @@
+use crate::backend::{Backend, LlmBackend};
+use crate::error::DmsError;
+use crate::llm::{self, Task};
+use crate::psql::{self, Database};
+use crate::query::{self, SearchMode, SearchOptions, Sort};
+use crate::settings::{Config, DocumentPage, SearchResult};
+use crate::tokens;
+
+// Documents given to the model as context.
+const CONTEXT_DOCUMENTS: usize = 5;
+
+// The question as full-text query matching any of its words, documents containing more of them rank higher.
+// websearch_to_tsquery reads "or" as the OR operator, stop words like "when" are dropped by the text search configuration.
+fn any_word_query(question: &str) -> String {
+    question.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty() && !word.eq_ignore_ascii_case("or"))
+        .collect::<Vec<_>>()
+        .join(" or ")
+}
+
+// The documents of both result lists taken alternately, each only once, until CONTEXT_DOCUMENTS are taken.
+fn interleave(semantic: Vec<SearchResult>, fulltext: Vec<SearchResult>) -> Vec<SearchResult> {
+    let mut documents: Vec<SearchResult> = Vec::new();
+    let mut lists = [semantic.into_iter(), fulltext.into_iter()];
+    while documents.len() < CONTEXT_DOCUMENTS {
+        let next: Vec<SearchResult> = lists.iter_mut().filter_map(|list| list.next()).collect();
+        if next.is_empty() {
+            break;
+        }
+        for document in next {
+            if documents.len() < CONTEXT_DOCUMENTS && !documents.iter().any(|d| d._id == document._id) {
+                documents.push(document);
+            }
+        }
+    }
+    documents
+}
+
+// The most relevant documents for the question: found by the full-text search and,
+// with an embedding model, by the semantic search, taken alternately from both.
+async fn retrieve(config: &Config, db: &Database, question: &str) -> Result<Vec<SearchResult>, DmsError> {
+    let query = query::parse(&any_word_query(question))?;
+    let mut options = SearchOptions::new(Sort::Rank, config.fts_config());
+    options.limit = Some(CONTEXT_DOCUMENTS as u32);
+
+    options.mode = SearchMode::Fulltext;
+    let (fulltext, _) = psql::search(db, &query, &options).await?;
+    let semantic = match &config.embedding_model {
+        Some(model) => {
+            options.mode = SearchMode::Semantic;
+            options.embedding = Some(llm::embedding(config, question.to_string(), model).await?);
+            psql::search(db, &query, &options).await?.0
+        }
+        None => Vec::new(),
+    };
+
+    Ok(interleave(semantic, fulltext))
+}
+
+// Excerpt of the document for the prompt: its pages, the best matching one first, each headed by its source [id:page].
+// Pages are added until max_tokens are reached, the last one is cut.
+fn excerpt(document: &SearchResult, mut pages: Vec<DocumentPage>, max_tokens: usize) -> String {
+    pages.sort_by_key(|page| (Some(page.page) != document.page, page.page));
+    let mut excerpt = String::new();
+    let mut left = max_tokens;
+    for page in pages {
+        let text = format!("[{}:{}] {}: {}", document._id, page.page, document.title, page.content);
+        let text = match tokens::estimate(&text) <= left {
+            true => text,
+            false => tokens::split(&text, left).into_iter().next().unwrap_or_default(),
+        };
+        left = left.saturating_sub(tokens::estimate(&text));
+        excerpt.push_str(&text);
+        excerpt.push('\n');
+        if left == 0 {
+            break;
+        }
+    }
+    excerpt
+}
+
+// Prompt of the excerpts of the documents with their pages and the question. The documents share
+// the context window of the model left by the system prompt, the question and the answer.
+fn prompt(config: &Config, question: &str, documents: Vec<(&SearchResult, Vec<DocumentPage>)>) -> String {
+    let question = format!("{}: {}", llm::prompts(config).question, question.trim());
+    let budget = llm::prompt_budget(config, Task::Answer).saturating_sub(tokens::estimate(&question));
+    let share = budget / documents.len().max(1);
+    let excerpts: Vec<String> = documents.into_iter().map(|(document, pages)| excerpt(document, pages, share)).collect();
+    format!("{}\n{}", excerpts.join("\n"), question)
+}
+
+// Answer the <question> from the stored documents with the local LLM and list the documents it was given.
+pub async fn ask(config: &Config, db: &Database, question: &str) -> Result<(), DmsError> {
+    if question.trim().is_empty() {
+        return Err(DmsError::EmptyQuery);
+    }
+    let documents = retrieve(config, db, question).await?;
+    if documents.is_empty() {
+        println!("No documents found for the question");
+        return Ok(());
+    }
+
+    let mut pages = Vec::new();
+    for document in &documents {
+        pages.push((document, psql::pages(db, document._id).await?));
+    }
+    let prompt = prompt(config, question, pages);
+    print!("{}", answer_with_sources(&Backend::new(config)?, config, prompt, &documents).await?);
+    Ok(())
+}
+
+// The answer of the model to the prompt, followed by the documents it was given as sources.
+async fn answer_with_sources(backend: &impl LlmBackend, config: &Config, prompt: String, documents: &[SearchResult]) -> Result<String, DmsError> {
+    let mut text = format!("{}\n\nSources:\n", llm::answer(backend, config, prompt).await?);
+    for document in documents {
+        let page = document.page.map(|page| format!(":{}", page)).unwrap_or_default();
+        text.push_str(&format!("  [{}{}] {} ({})\n", document._id, page, document.title, document.date));
+    }
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MockBackend;
+    use crate::stub_server::StubServer;
+    use chrono::NaiveDate;
+
+    fn document(id: i32, title: &str, page: Option<i32>) -> SearchResult {
+        SearchResult {
+            _id: id,
+            title: title.to_string(),
+            date: NaiveDate::from_ymd_opt(2023, 1, 15).unwrap(),
+            rank: 0.5,
+            page,
+            tags: Vec::new(),
+            correspondent: None,
+            fields: Vec::new(),
+            snippet: None,
+        }
+    }
+
+    fn ids(documents: &[SearchResult]) -> Vec<i32> {
+        documents.iter().map(|d| d._id).collect()
+    }
+
+    #[test]
+    fn question_as_any_word() {
+        assert_eq!(any_word_query("When does my car-insurance renew, or not?"), "When or does or my or car or insurance or renew or not");
+        assert_eq!(any_word_query(" ?! "), "");
+    }
+
+    #[test]
+    fn interleave_semantic_and_fulltext() {
+        let semantic = vec![document(1, "a", None), document(2, "b", None), document(3, "c", None)];
+        let fulltext = vec![document(4, "d", None), document(1, "a", Some(1))];
+        assert_eq!(ids(&interleave(semantic, fulltext)), vec![1, 4, 2, 3]);
+        assert!(interleave(Vec::new(), Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn interleave_up_to_the_context_documents() {
+        let semantic = (1..=4).map(|id| document(id, "s", None)).collect();
+        let fulltext = (11..=14).map(|id| document(id, "f", None)).collect();
+        assert_eq!(ids(&interleave(semantic, fulltext)), vec![1, 11, 2, 12, 3]);
+        let fulltext = (11..=17).map(|id| document(id, "f", None)).collect();
+        assert_eq!(ids(&interleave(Vec::new(), fulltext)), vec![11, 12, 13, 14, 15]);
+    }
+
+    fn page(page: i32, content: &str) -> DocumentPage {
+        DocumentPage { _id: 7, page, content: content.to_string(), method: "ocr".to_string() }
+    }
+
+    // Text of n words of a single token each.
+    fn words(n: usize) -> String {
+        vec!["ab"; n].join(" ")
+    }
+
+    #[test]
+    fn excerpt_best_page_first() {
+        let pages = || vec![page(1, "Eins"), page(2, "Zwei"), page(3, "Drei")];
+        let text = excerpt(&document(7, "Strom", Some(2)), pages(), 100);
+        assert_eq!(text, "[7:2] Strom: Zwei\n[7:1] Strom: Eins\n[7:3] Strom: Drei\n");
+        let text = excerpt(&document(7, "Strom", None), pages(), 100);
+        assert_eq!(text, "[7:1] Strom: Eins\n[7:2] Strom: Zwei\n[7:3] Strom: Drei\n");
+    }
+
+    #[test]
+    fn excerpt_cuts_the_last_page() {
+        // Every page is its header of 4 tokens and 20 words
+        let pages = vec![page(1, &words(20)), page(2, &words(20)), page(3, &words(20))];
+        let text = excerpt(&document(7, "Strom", None), pages, 30);
+        assert_eq!(tokens::estimate(&text), 30);
+        assert_eq!(text, format!("[7:1] Strom: {}\n[7:2] Strom: {}\n", words(20), words(2)));
+    }
+
+    #[test]
+    fn prompt_shares_the_budget() {
+        let mut config = Config::default();
+        config.llm.context_windows.insert("doc_answerer".to_string(), 1024);
+        let budget = llm::prompt_budget(&config, Task::Answer);
+        let (long, short) = (document(7, "Strom", None), document(8, "Miete", None));
+        let prompt = prompt(&config, " Wann? ", vec![(&long, vec![page(1, &words(1000))]), (&short, vec![page(1, "kurz")])]);
+        assert!(prompt.ends_with("\nFrage: Wann?"), "{}", prompt);
+        assert!(tokens::estimate(&prompt) <= budget);
+        let (first, rest) = prompt.split_once("\n[8:1]").unwrap();
+        // The long document gets its share of the budget, even if the other one does not need its own
+        let question = tokens::estimate("Frage: Wann?");
+        assert_eq!(tokens::estimate(first), (budget - question) / 2);
+        assert!(rest.starts_with(" Miete: kurz\n"));
+    }
+
+    #[tokio::test]
+    async fn answer_lists_the_sources() {
+        let mock = MockBackend::with_answers(&["  Die Rechnung ist vom 15.01.2023 [7:2].\n"]);
+        let documents = [document(7, "Stromrechnung", Some(2)), document(12, "Mietvertrag", None)];
+        let text = answer_with_sources(&mock, &Config::default(), "[7:2] Stromrechnung: ...".to_string(), &documents).await.unwrap();
+        assert_eq!(text, "Die Rechnung ist vom 15.01.2023 [7:2].\n\nSources:\n  [7:2] Stromrechnung (2023-01-15)\n  [12] Mietvertrag (2023-01-15)\n");
+        assert_eq!(mock.prompts(), vec!["[7:2] Stromrechnung: ..."]);
+    }
+
+    #[tokio::test]
+    async fn ask_the_ollama_server() {
+        let server = StubServer::start(&[
+            ("/api/embeddings", 200, r#"{"embedding": [0.5, -0.25]}"#),
+            ("/api/generate", 200, r#"{"model": "doc_answerer", "created_at": "2024-01-01T00:00:00Z", "response": "Im Januar [7:2].", "done": true}"#),
+        ]).await;
+        let mut config = Config::default();
+        config.llm.url = Some(server.url.clone());
+        let backend = Backend::new(&config).unwrap();
+
+        assert_eq!(llm::embedding(&config, "Wann?".to_string(), "nomic-embed-text").await.unwrap(), vec![0.5, -0.25]);
+        let documents = [document(7, "Stromrechnung", Some(2))];
+        let prompt = prompt(&config, "Wann?", vec![(&documents[0], vec![page(1, "Seite eins"), page(2, "Januar 2023")])]);
+        let text = answer_with_sources(&backend, &config, prompt, &documents).await.unwrap();
+        assert_eq!(text, "Im Januar [7:2].\n\nSources:\n  [7:2] Stromrechnung (2023-01-15)\n");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].path, "/api/embeddings");
+        assert_eq!(requests[0].body["model"], "nomic-embed-text");
+        assert_eq!(requests[0].body["prompt"], "Wann?");
+        assert_eq!(requests[1].path, "/api/generate");
+        assert_eq!(requests[1].body["model"], "doc_answerer");
+        assert_eq!(requests[1].body["system"], llm::prompts(&config).answer);
+        assert_eq!(requests[1].body["prompt"], "[7:2] Stromrechnung: Januar 2023\n[7:1] Stromrechnung: Seite eins\n\nFrage: Wann?");
+    }
+}
@@ -0,0 +1,508 @@
+use ollama_rs::generation::completion::request::GenerationRequest;
+use ollama_rs::generation::options::GenerationOptions;
+use ollama_rs::Ollama;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::error::DmsError;
+use crate::settings::{self, Config, ConfigError, LlmBackendKind};
+
+// Dimensions of the embeddings of the mock backend.
+const MOCK_DIMENSIONS: usize = 64;
+// Words of the prompt the mock backend answers with.
+const MOCK_WORDS: usize = 12;
+
+// One request to a LLM.
+pub struct LlmRequest {
+    pub model: String,
+    pub system: String,
+    pub temperature: f32,
+    pub prompt: String,
+    // JSON schema the answer has to follow, a free text answer if None
+    pub format: Option<serde_json::Value>,
+    // Context window in tokens, the one of the model on the server if None
+    pub context_window: Option<u32>,
+}
+
+// A server (or stand-in) that answers prompts and computes embeddings.
+pub trait LlmBackend {
+    async fn generate(&self, request: &LlmRequest) -> Result<String, DmsError>;
+    async fn embed(&self, model: &str, text: &str) -> Result<Vec<f32>, DmsError>;
+}
+
+fn llm_error(model: &str, reason: impl ToString) -> DmsError {
+    DmsError::Llm { model: model.to_string(), reason: reason.to_string() }
+}
+
+#[derive(Deserialize)]
+struct GenerateResponse {
+    response: String,
+}
+
+// Ollama through ollama_rs.
+// Requests with a JSON schema are posted to /api/generate directly, as ollama_rs only knows the plain JSON format.
+pub struct OllamaBackend {
+    ollama: Ollama,
+    client: reqwest::Client,
+}
+
+impl OllamaBackend {
+    // Server of the config, from OLLAMA_HOST like the ollama CLI (e.g. "http://127.0.0.1:11434") or the local default.
+    // OLLAMA_HOST may leave out the scheme, the ollama CLI takes http then.
+    pub fn new(config: &Config) -> Result<OllamaBackend, ConfigError> {
+        let (name, url) = match (&config.llm.url, std::env::var("OLLAMA_HOST")) {
+            (Some(url), _) => ("llm.url", url.clone()),
+            (None, Ok(host)) if host.contains("://") => ("OLLAMA_HOST", host),
+            (None, Ok(host)) => ("OLLAMA_HOST", format!("http://{}", host)),
+            (None, Err(_)) => return Ok(OllamaBackend { ollama: Ollama::default(), client: reqwest::Client::new() }),
+        };
+        let parsed = settings::parse_url(name, &url)?;
+        // ollama_rs only takes the host and the port, so a path would be lost.
+        let (Some(host), Some(port), "/") = (parsed.host_str(), parsed.port_or_known_default(), parsed.path()) else {
+            return Err(ConfigError::InvalidUrl(name, url));
+        };
+        let ollama = Ollama::new(format!("{}://{}", parsed.scheme(), host), port);
+        Ok(OllamaBackend { ollama, client: reqwest::Client::new() })
+    }
+
+    // Answer that follows the JSON schema (structured outputs of Ollama 0.5 and later).
+    async fn generate_structured(&self, request: &LlmRequest, schema: &serde_json::Value) -> Result<String, DmsError> {
+        let mut body = json!({
+            "model": request.model,
+            "system": request.system,
+            "prompt": request.prompt,
+            "format": schema,
+            "stream": false,
+            "options": { "temperature": request.temperature },
+        });
+        if let Some(context_window) = request.context_window {
+            body["options"]["num_ctx"] = json!(context_window);
+        }
+        let res = self.client.post(format!("{}/api/generate", self.ollama.uri()))
+            .header("Content-Type", "application/json")
+            .body(body.to_string())
+            .send().await
+            .map_err(|e| llm_error(&request.model, e))?;
+        let status = res.status();
+        let bytes = res.bytes().await.map_err(|e| llm_error(&request.model, e))?;
+        if !status.is_success() {
+            return Err(llm_error(&request.model, format!("{}: {}", status, String::from_utf8_lossy(&bytes))));
+        }
+        let res: GenerateResponse = serde_json::from_slice(&bytes).map_err(|e| llm_error(&request.model, e))?;
+        Ok(res.response)
+    }
+}
+
+impl LlmBackend for OllamaBackend {
+    async fn generate(&self, request: &LlmRequest) -> Result<String, DmsError> {
+        if let Some(schema) = &request.format {
+            return self.generate_structured(request, schema).await;
+        }
+        let mut options = GenerationOptions::default().temperature(request.temperature);
+        if let Some(context_window) = request.context_window {
+            options = options.num_ctx(context_window);
+        }
+        let generation = GenerationRequest::new(request.model.clone(), request.prompt.clone())
+            .system(request.system.clone())
+            .options(options);
+        let res = self.ollama.generate(generation).await.map_err(|e| llm_error(&request.model, e))?;
+        Ok(res.response)
+    }
+
+    async fn embed(&self, model: &str, text: &str) -> Result<Vec<f32>, DmsError> {
+        let res = self.ollama.generate_embeddings(model.to_string(), text.to_string(), None).await
+            .map_err(|e| llm_error(model, e))?;
+        Ok(res.embeddings.into_iter().map(|x| x as f32).collect())
+    }
+}
+
+#[derive(Deserialize)]
+struct ChatResponse {
+    choices: Vec<ChatChoice>,
+}
+
+#[derive(Deserialize)]
+struct ChatChoice {
+    message: ChatMessage,
+}
+
+#[derive(Deserialize)]
+struct ChatMessage {
+    content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingsResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    embedding: Vec<f32>,
+}
+
+// Servers with the OpenAI API (/v1/chat/completions), e.g. llama.cpp server, LM Studio or vLLM.
+pub struct OpenAiBackend {
+    url: String,
+    api_key: Option<String>,
+    client: reqwest::Client,
+}
+
+impl OpenAiBackend {
+    // Server of the config, the llama.cpp server default http://127.0.0.1:8080 if not set.
+    pub fn new(config: &Config) -> OpenAiBackend {
+        let url = config.llm.url.clone().unwrap_or("http://127.0.0.1:8080".to_string());
+        OpenAiBackend {
+            url: url.trim_end_matches('/').trim_end_matches("/v1").to_string(),
+            api_key: config.llm.api_key.clone(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    // POST the body to the endpoint below /v1 and parse the JSON answer.
+    async fn post<T: for<'de> Deserialize<'de>>(&self, model: &str, endpoint: &str, body: serde_json::Value) -> Result<T, DmsError> {
+        let mut request = self.client.post(format!("{}/v1/{}", self.url, endpoint))
+            .header("Content-Type", "application/json")
+            .body(body.to_string());
+        if let Some(key) = &self.api_key {
+            request = request.bearer_auth(key);
+        }
+        let res = request.send().await.map_err(|e| llm_error(model, e))?;
+        let status = res.status();
+        let bytes = res.bytes().await.map_err(|e| llm_error(model, e))?;
+        if !status.is_success() {
+            return Err(llm_error(model, format!("{}: {}", status, String::from_utf8_lossy(&bytes))));
+        }
+        serde_json::from_slice(&bytes).map_err(|e| llm_error(model, e))
+    }
+}
+
+impl LlmBackend for OpenAiBackend {
+    async fn generate(&self, request: &LlmRequest) -> Result<String, DmsError> {
+        let mut body = json!({
+            "model": request.model,
+            "temperature": request.temperature,
+            "messages": [
+                { "role": "system", "content": request.system },
+                { "role": "user", "content": request.prompt },
+            ],
+        });
+        if let Some(schema) = &request.format {
+            body["response_format"] = json!({
+                "type": "json_schema",
+                "json_schema": { "name": "document", "schema": schema },
+            });
+        }
+        let res: ChatResponse = self.post(&request.model, "chat/completions", body).await?;
+        Ok(res.choices.into_iter().next().and_then(|choice| choice.message.content).unwrap_or_default())
+    }
+
+    async fn embed(&self, model: &str, text: &str) -> Result<Vec<f32>, DmsError> {
+        let res: EmbeddingsResponse = self.post(model, "embeddings", json!({ "model": model, "input": text })).await?;
+        Ok(res.data.into_iter().next().map(|data| data.embedding).unwrap_or_default())
+    }
+}
+
+// Deterministic stand-in without a server, to try DMSLite out and to test it.
+// Answers with the first words of the prompt (as JSON object with these words in every string property
+// if a schema is requested), embeds the words of the text into hashed buckets.
+// Tests can script the answers instead, and read the prompts it was given.
+#[derive(Default)]
+pub struct MockBackend {
+    answers: Mutex<VecDeque<String>>,
+    prompts: Mutex<Vec<String>>,
+}
+
+impl MockBackend {
+    // Answers with the given answers in order, then like the default mock.
+    #[cfg(test)]
+    pub fn with_answers(answers: &[&str]) -> MockBackend {
+        MockBackend { answers: Mutex::new(answers.iter().map(|a| a.to_string()).collect()), prompts: Mutex::default() }
+    }
+
+    // The prompts of all requests so far.
+    #[cfg(test)]
+    pub fn prompts(&self) -> Vec<String> {
+        self.prompts.lock().unwrap().clone()
+    }
+}
+
+// FNV-1a, stable across Rust versions unlike the std hasher.
+fn fnv1a(word: &str) -> u64 {
+    word.bytes().fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+impl LlmBackend for MockBackend {
+    async fn generate(&self, request: &LlmRequest) -> Result<String, DmsError> {
+        self.prompts.lock().unwrap().push(request.prompt.clone());
+        if let Some(answer) = self.answers.lock().unwrap().pop_front() {
+            return Ok(answer);
+        }
+        let words: Vec<&str> = request.prompt.split_whitespace().take(MOCK_WORDS).collect();
+        let text = format!("mock {} {}", request.model, words.join(" "));
+        let Some(properties) = request.format.as_ref().and_then(|schema| schema["properties"].as_object()) else {
+            return Ok(text);
+        };
+        let mut answer = serde_json::Map::new();
+        for (name, property) in properties {
+            let value = match property["type"].as_str() {
+                Some("string") => json!(text),
+                Some("array") => json!([]),
+                _ => serde_json::Value::Null,
+            };
+            answer.insert(name.clone(), value);
+        }
+        Ok(serde_json::Value::Object(answer).to_string())
+    }
+
+    async fn embed(&self, _model: &str, text: &str) -> Result<Vec<f32>, DmsError> {
+        let mut vector = vec![0.0; MOCK_DIMENSIONS];
+        for word in text.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()) {
+            vector[(fnv1a(&word.to_lowercase()) % MOCK_DIMENSIONS as u64) as usize] += 1.0;
+        }
+        Ok(vector)
+    }
+}
+
+// The backend chosen in the config.
+pub enum Backend {
+    Ollama(OllamaBackend),
+    OpenAi(OpenAiBackend),
+    Mock(MockBackend),
+}
+
+impl Backend {
+    pub fn new(config: &Config) -> Result<Backend, ConfigError> {
+        Ok(match config.llm.backend {
+            LlmBackendKind::Ollama => Backend::Ollama(OllamaBackend::new(config)?),
+            LlmBackendKind::Openai => Backend::OpenAi(OpenAiBackend::new(config)),
+            LlmBackendKind::Mock => Backend::Mock(MockBackend::default()),
+        })
+    }
+}
+
+impl LlmBackend for Backend {
+    async fn generate(&self, request: &LlmRequest) -> Result<String, DmsError> {
+        match self {
+            Backend::Ollama(backend) => backend.generate(request).await,
+            Backend::OpenAi(backend) => backend.generate(request).await,
+            Backend::Mock(backend) => backend.generate(request).await,
+        }
+    }
+
+    async fn embed(&self, model: &str, text: &str) -> Result<Vec<f32>, DmsError> {
+        match self {
+            Backend::Ollama(backend) => backend.embed(model, text).await,
+            Backend::OpenAi(backend) => backend.embed(model, text).await,
+            Backend::Mock(backend) => backend.embed(model, text).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_server::StubServer;
+
+    fn request(prompt: &str) -> LlmRequest {
+        LlmRequest { model: "m".to_string(), system: "sys".to_string(), temperature: 0.5, prompt: prompt.to_string(), format: None, context_window: None }
+    }
+
+    fn config(url: &str) -> Config {
+        let mut config = Config::default();
+        config.llm.url = Some(url.to_string());
+        config
+    }
+
+    fn reason(result: Result<impl std::fmt::Debug, DmsError>) -> String {
+        match result {
+            Err(DmsError::Llm { reason, .. }) => reason,
+            other => panic!("no LLM error: {:?}", other),
+        }
+    }
+
+    const OLLAMA_ANSWER: &str = r#"{"model": "m", "created_at": "2024-01-01T00:00:00Z", "response": "Hallo", "done": true}"#;
+
+    #[tokio::test]
+    async fn ollama_generate() {
+        let server = StubServer::start(&[("/api/generate", 200, OLLAMA_ANSWER)]).await;
+        let backend = OllamaBackend::new(&config(&server.url)).unwrap();
+        assert_eq!(backend.generate(&request("Frage")).await.unwrap(), "Hallo");
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        let body = &requests[0].body;
+        assert_eq!((body["model"].as_str(), body["prompt"].as_str(), body["system"].as_str()), (Some("m"), Some("Frage"), Some("sys")));
+        assert_eq!(body["stream"], json!(false));
+        assert_eq!(body["options"]["temperature"], json!(0.5));
+    }
+
+    #[tokio::test]
+    async fn ollama_embed() {
+        let server = StubServer::start(&[("/api/embeddings", 200, r#"{"embedding": [0.5, -1.0]}"#)]).await;
+        let backend = OllamaBackend::new(&config(&server.url)).unwrap();
+        assert_eq!(backend.embed("e", "Text").await.unwrap(), vec![0.5, -1.0]);
+        let body = &server.requests()[0].body;
+        assert_eq!((body["model"].as_str(), body["prompt"].as_str()), (Some("e"), Some("Text")));
+    }
+
+    #[tokio::test]
+    async fn ollama_errors() {
+        let server = StubServer::start(&[
+            ("/api/generate", 404, r#"{"error": "model 'm' not found"}"#),
+            ("/api/embeddings", 200, "no json"),
+        ]).await;
+        let backend = OllamaBackend::new(&config(&server.url)).unwrap();
+        assert!(reason(backend.generate(&request("Frage")).await).contains("model 'm' not found"));
+        assert!(backend.embed("e", "Text").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn openai_generate() {
+        let server = StubServer::start(&[("/v1/chat/completions", 200, r#"{"choices": [{"message": {"content": "Hallo"}}]}"#)]).await;
+        let mut config = config(&format!("{}/v1/", server.url));
+        config.llm.api_key = Some("secret".to_string());
+        let backend = OpenAiBackend::new(&config);
+        assert_eq!(backend.generate(&request("Frage")).await.unwrap(), "Hallo");
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/v1/chat/completions");
+        assert_eq!(requests[0].headers.get("authorization").map(String::as_str), Some("Bearer secret"));
+        let body = &requests[0].body;
+        assert_eq!(body["model"], json!("m"));
+        assert_eq!(body["temperature"], json!(0.5));
+        assert_eq!(body["messages"], json!([{ "role": "system", "content": "sys" }, { "role": "user", "content": "Frage" }]));
+    }
+
+    #[tokio::test]
+    async fn openai_without_api_key_or_content() {
+        let server = StubServer::start(&[("/v1/chat/completions", 200, r#"{"choices": [{"message": {"content": null}}]}"#)]).await;
+        let backend = OpenAiBackend::new(&config(&server.url));
+        assert_eq!(backend.generate(&request("Frage")).await.unwrap(), "");
+        assert_eq!(server.requests()[0].headers.get("authorization"), None);
+    }
+
+    #[tokio::test]
+    async fn openai_embed() {
+        let server = StubServer::start(&[("/v1/embeddings", 200, r#"{"data": [{"embedding": [0.25, 2.0]}]}"#)]).await;
+        let backend = OpenAiBackend::new(&config(&server.url));
+        assert_eq!(backend.embed("e", "Text").await.unwrap(), vec![0.25, 2.0]);
+        assert_eq!(server.requests()[0].body, json!({ "model": "e", "input": "Text" }));
+    }
+
+    #[tokio::test]
+    async fn openai_errors() {
+        let server = StubServer::start(&[
+            ("/v1/chat/completions", 401, r#"{"error": "invalid api key"}"#),
+            ("/v1/embeddings", 200, r#"{"object": "list"}"#),
+        ]).await;
+        let backend = OpenAiBackend::new(&config(&server.url));
+        let reason = reason(backend.generate(&request("Frage")).await);
+        assert!(reason.starts_with("401 Unauthorized") && reason.contains("invalid api key"), "{}", reason);
+        assert!(backend.embed("e", "Text").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn mock_answers_with_the_prompt() {
+        let answer = MockBackend::default().generate(&request("eins zwei drei")).await.unwrap();
+        assert_eq!(answer, "mock m eins zwei drei");
+    }
+
+    #[tokio::test]
+    async fn mock_scripted_answers() {
+        let mock = MockBackend::with_answers(&["first", "second"]);
+        assert_eq!(mock.generate(&request("a")).await.unwrap(), "first");
+        assert_eq!(mock.generate(&request("b")).await.unwrap(), "second");
+        assert_eq!(mock.generate(&request("c")).await.unwrap(), "mock m c");
+        assert_eq!(mock.prompts(), vec!["a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn mock_embeddings() {
+        let mock = MockBackend::default();
+        let rent = mock.embed("e", "Rent, rent").await.unwrap();
+        assert_eq!(rent.len(), MOCK_DIMENSIONS);
+        assert_eq!(rent.iter().sum::<f32>(), 2.0);
+        assert_eq!(rent, mock.embed("e", "RENT rent").await.unwrap());
+    }
+
+    fn schema() -> serde_json::Value {
+        json!({ "type": "object", "properties": { "title": { "type": "string" }, "keywords": { "type": "array" } } })
+    }
+
+    #[tokio::test]
+    async fn ollama_generate_structured() {
+        let server = StubServer::start(&[("/api/generate", 200, r#"{"response": "{\"title\": \"Brief\"}"}"#)]).await;
+        let backend = OllamaBackend::new(&config(&server.url)).unwrap();
+        let mut request = request("Frage");
+        request.format = Some(schema());
+        assert_eq!(backend.generate(&request).await.unwrap(), r#"{"title": "Brief"}"#);
+        let body = &server.requests()[0].body;
+        assert_eq!(body["format"], schema());
+        assert_eq!((body["stream"].clone(), body["system"].clone()), (json!(false), json!("sys")));
+        assert_eq!(body["options"], json!({ "temperature": 0.5 }));
+    }
+
+    #[tokio::test]
+    async fn ollama_generate_structured_errors() {
+        let server = StubServer::start(&[("/api/generate", 500, r#"{"error": "format not supported"}"#)]).await;
+        let backend = OllamaBackend::new(&config(&server.url)).unwrap();
+        let mut request = request("Frage");
+        request.format = Some(schema());
+        let reason = reason(backend.generate(&request).await);
+        assert!(reason.starts_with("500 Internal Server Error") && reason.contains("format not supported"), "{}", reason);
+    }
+
+    #[tokio::test]
+    async fn openai_generate_structured() {
+        let server = StubServer::start(&[("/v1/chat/completions", 200, r#"{"choices": [{"message": {"content": "{}"}}]}"#)]).await;
+        let backend = OpenAiBackend::new(&config(&server.url));
+        let mut request = request("Frage");
+        request.format = Some(schema());
+        assert_eq!(backend.generate(&request).await.unwrap(), "{}");
+        let body = &server.requests()[0].body;
+        assert_eq!(body["response_format"], json!({ "type": "json_schema", "json_schema": { "name": "document", "schema": schema() } }));
+    }
+
+    #[tokio::test]
+    async fn mock_answers_following_the_schema() {
+        let mut request = request("eins");
+        request.format = Some(schema());
+        let answer = MockBackend::default().generate(&request).await.unwrap();
+        let answer: serde_json::Value = serde_json::from_str(&answer).unwrap();
+        assert_eq!(answer, json!({ "title": "mock m eins", "keywords": [] }));
+    }
+
+    #[tokio::test]
+    async fn ollama_context_window() {
+        let server = StubServer::start(&[("/api/generate", 200, OLLAMA_ANSWER)]).await;
+        let backend = OllamaBackend::new(&config(&server.url)).unwrap();
+        let mut request = request("Frage");
+        request.context_window = Some(8192);
+        backend.generate(&request).await.unwrap();
+        request.format = Some(schema());
+        backend.generate(&request).await.unwrap();
+        let requests = server.requests();
+        assert_eq!(requests[0].body["options"]["num_ctx"], json!(8192));
+        assert_eq!(requests[1].body["options"], json!({ "temperature": 0.5, "num_ctx": 8192 }));
+    }
+
+    fn ollama_uri(url: &str) -> Result<String, ConfigError> {
+        OllamaBackend::new(&config(url)).map(|backend| backend.ollama.uri())
+    }
+
+    #[test]
+    fn ollama_urls() {
+        assert_eq!(ollama_uri("http://127.0.0.1:11434").unwrap(), "http://127.0.0.1:11434");
+        assert_eq!(ollama_uri("http://localhost:11500/").unwrap(), "http://localhost:11500");
+        assert_eq!(ollama_uri("https://ollama.example.org").unwrap(), "https://ollama.example.org:443");
+        assert_eq!(ollama_uri("http://[::1]:11434").unwrap(), "http://[::1]:11434");
+    }
+
+    #[test]
+    fn invalid_ollama_urls() {
+        for url in ["localhost:11434", "127.0.0.1", "http://", "ftp://127.0.0.1", "http://127.0.0.1:11434/api", "http://127.0.0.1:99999"] {
+            assert!(matches!(ollama_uri(url), Err(ConfigError::InvalidUrl("llm.url", _))), "{}", url);
+        }
+    }
+}
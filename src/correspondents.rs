@@ -0,0 +1,137 @@
+use deadpool_postgres::GenericClient;
+
+use crate::error::DmsError;
+use crate::psql::{Database, DbError};
+
+// Shorter known names would match by chance somewhere in the content.
+const MIN_MATCH_CHARS: i32 = 4;
+
+// SQL expression with the correspondent name of the Document row aliased as m,
+// a proposed but not yet confirmed correspondent is marked with a trailing "?".
+pub fn correspondent_column() -> String {
+    "(SELECT co.name || CASE WHEN m.correspondent_confirmed THEN '' ELSE '?' END
+        FROM dmslite.correspondents co WHERE co.id = m.correspondent_id)".to_string()
+}
+
+// Rule-based proposal: the longest known correspondent whose name appears in the content.
+pub async fn find_in_content(db: &Database, content: &str) -> Result<Option<String>, DbError> {
+    let row = db.client().await?.query_opt(
+        "SELECT name FROM dmslite.correspondents
+        WHERE length(name) >= $2 AND strpos(lower($1), lower(name)) > 0
+        ORDER BY length(name) DESC, id ASC
+        LIMIT 1",
+        &[&content, &MIN_MATCH_CHARS],
+    ).await?;
+    Ok(row.map(|row| row.get(0)))
+}
+
+// Id of the correspondent with the name (case-insensitive), created if it does not exist yet.
+async fn correspondent_id(client: &impl GenericClient, name: &str) -> Result<i32, DbError> {
+    client.execute(
+        "INSERT INTO dmslite.correspondents (name) VALUES ($1) ON CONFLICT ((lower(name))) DO NOTHING",
+        &[&name],
+    ).await?;
+    let row = client.query_one("SELECT id FROM dmslite.correspondents WHERE lower(name) = lower($1)", &[&name]).await?;
+    Ok(row.get(0))
+}
+
+// Overridden proposals and correspondents of deleted Documents would be matched in the content of new Documents,
+// so drop correspondents without Documents.
+pub async fn remove_unused(client: &impl GenericClient) -> Result<(), DbError> {
+    client.execute(
+        "DELETE FROM dmslite.correspondents co
+        WHERE NOT EXISTS (SELECT 1 FROM dmslite.main_table m WHERE m.correspondent_id = co.id)",
+        &[],
+    ).await?;
+    Ok(())
+}
+
+// Store the proposed correspondent of the Document with the <id>, it is not confirmed.
+pub async fn propose(client: &impl GenericClient, id: i32, name: &str) -> Result<(), DbError> {
+    let correspondent = correspondent_id(client, name).await?;
+    client.execute(
+        "UPDATE dmslite.main_table SET correspondent_id = $2, correspondent_confirmed = false WHERE id = $1",
+        &[&id, &correspondent],
+    ).await?;
+    Ok(())
+}
+
+// Set and confirm the correspondent of the Document with the <id>, overriding the proposed one.
+pub async fn set(db: &Database, id: i32, name: &str) -> Result<(), DmsError> {
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    if name.is_empty() {
+        return Err(DmsError::InvalidCorrespondent(name));
+    }
+    let mut client = db.client().await?;
+    // A new correspondent is only created for an existing Document, in one transaction with its update.
+    let transaction = client.transaction().await?;
+    let document = transaction.query_opt("SELECT 1 FROM dmslite.main_table WHERE id = $1 FOR UPDATE", &[&id]).await?;
+    if document.is_none() {
+        return Err(DmsError::NotFound(id));
+    }
+    let correspondent = correspondent_id(&transaction, &name).await?;
+    transaction.execute(
+        "UPDATE dmslite.main_table SET correspondent_id = $2, correspondent_confirmed = true WHERE id = $1",
+        &[&id, &correspondent],
+    ).await?;
+    remove_unused(&transaction).await?;
+    transaction.commit().await?;
+    println!("Correspondent of document {} is {}", id, name);
+    Ok(())
+}
+
+// Confirm the proposed correspondent of the Document with the <id>.
+pub async fn confirm(db: &Database, id: i32) -> Result<(), DmsError> {
+    let client = db.client().await?;
+    let row = client.query_opt(
+        "UPDATE dmslite.main_table m SET correspondent_confirmed = true
+        FROM dmslite.correspondents co
+        WHERE m.id = $1 AND co.id = m.correspondent_id
+        RETURNING co.name",
+        &[&id],
+    ).await?;
+    match row {
+        Some(row) => {
+            println!("Correspondent of document {} is {}", id, row.get::<_, String>(0));
+            Ok(())
+        }
+        None => Err(DmsError::NoCorrespondent(id)),
+    }
+}
+
+// Remove the correspondent from the Document with the <id>.
+pub async fn unset(db: &Database, id: i32) -> Result<(), DmsError> {
+    let client = db.client().await?;
+    let updated = client.execute(
+        "UPDATE dmslite.main_table SET correspondent_id = NULL, correspondent_confirmed = false WHERE id = $1",
+        &[&id],
+    ).await?;
+    if updated == 0 {
+        return Err(DmsError::NotFound(id));
+    }
+    remove_unused(&client).await?;
+    println!("Removed the correspondent of document {}", id);
+    Ok(())
+}
+
+// List all correspondents with the number of their Documents.
+pub async fn list(db: &Database) -> Result<(), DmsError> {
+    let client = db.client().await?;
+    let rows = client.query(
+        "SELECT co.name, COUNT(m.id) FILTER (WHERE m.correspondent_confirmed), COUNT(m.id) FILTER (WHERE NOT m.correspondent_confirmed)
+        FROM dmslite.correspondents co LEFT JOIN dmslite.main_table m ON m.correspondent_id = co.id
+        GROUP BY co.name
+        ORDER BY lower(co.name)",
+        &[],
+    ).await?;
+    if rows.is_empty() {
+        println!("No correspondents");
+    }
+    for row in rows {
+        let name: String = row.get(0);
+        let confirmed: i64 = row.get(1);
+        let proposed: i64 = row.get(2);
+        println!("{} ({} documents, {} proposed)", name, confirmed, proposed);
+    }
+    Ok(())
+}
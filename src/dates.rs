@@ -0,0 +1,170 @@
+use chrono::{Datelike, NaiveDate, Utc};
+use regex::Regex;
+use std::sync::LazyLock;
+
+// Dates before are rather birth dates or references than the date of a document.
+const MIN_YEAR: i32 = 1900;
+
+// 12.03.2023, 12.3.23
+static GERMAN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b(\d{1,2})\.\s?(\d{1,2})\.\s?(\d{4}|\d{2})\b").unwrap());
+// 2023-03-12
+static ISO: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b(\d{4})-(\d{1,2})-(\d{1,2})\b").unwrap());
+// 12/03/2023 (day first) or 03/12/2023 (month first, US)
+static SLASH: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b(\d{1,2})/(\d{1,2})/(\d{4}|\d{2})\b").unwrap());
+// 12. Maerz 2023, 12 March 2023, 12th of March, 2023
+static DAY_MONTH: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)\b(\d{1,2})(?:\.|st|nd|rd|th)?\s+(?:of\s+)?(\p{L}{3,9})\.?,?\s+(\d{4})\b").unwrap()
+});
+// March 12, 2023
+static MONTH_DAY: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)\b(\p{L}{3,9})\.?\s+(\d{1,2})(?:st|nd|rd|th)?,?\s+(\d{4})\b").unwrap()
+});
+
+// German and English month names and their abbreviations.
+// Besides "März" also the spellings without umlaut, and "Mrz" of OCR that missed the umlaut.
+fn month(name: &str) -> Option<u32> {
+    let month = match name.to_lowercase().as_str() {
+        "jan" | "januar" | "january" => 1,
+        "feb" | "februar" | "february" => 2,
+        "mar" | "märz" | "mrz" | "maerz" | "marz" | "march" => 3,
+        "apr" | "april" => 4,
+        "mai" | "may" => 5,
+        "jun" | "juni" | "june" => 6,
+        "jul" | "juli" | "july" => 7,
+        "aug" | "august" => 8,
+        "sep" | "sept" | "september" => 9,
+        "okt" | "oct" | "oktober" | "october" => 10,
+        "nov" | "november" => 11,
+        "dez" | "dec" | "dezember" | "december" => 12,
+        _ => return None,
+    };
+    Some(month)
+}
+
+// Two digit years are in the past: 23 is 2023, 87 is 1987.
+fn year(digits: &str) -> Option<i32> {
+    let year: i32 = digits.parse().ok()?;
+    if digits.len() > 2 {
+        return Some(year);
+    }
+    let current = Utc::now().year();
+    match 2000 + year <= current {
+        true => Some(2000 + year),
+        false => Some(1900 + year),
+    }
+}
+
+fn date(year: Option<i32>, month: Option<u32>, day: &str) -> Option<NaiveDate> {
+    NaiveDate::from_ymd_opt(year?, month?, day.parse().ok()?)
+}
+
+// All dates in the text with their position.
+fn candidates(text: &str) -> Vec<(usize, NaiveDate)> {
+    let mut dates = Vec::new();
+    for c in GERMAN.captures_iter(text) {
+        dates.extend(date(year(&c[3]), c[2].parse().ok(), &c[1]).map(|d| (c.get(0).map_or(0, |m| m.start()), d)));
+    }
+    for c in ISO.captures_iter(text) {
+        dates.extend(date(year(&c[1]), c[2].parse().ok(), &c[3]).map(|d| (c.get(0).map_or(0, |m| m.start()), d)));
+    }
+    for c in SLASH.captures_iter(text) {
+        let day_first = date(year(&c[3]), c[2].parse().ok(), &c[1]);
+        let month_first = date(year(&c[3]), c[1].parse().ok(), &c[2]);
+        dates.extend(day_first.or(month_first).map(|d| (c.get(0).map_or(0, |m| m.start()), d)));
+    }
+    for c in DAY_MONTH.captures_iter(text) {
+        dates.extend(date(year(&c[3]), month(&c[2]), &c[1]).map(|d| (c.get(0).map_or(0, |m| m.start()), d)));
+    }
+    for c in MONTH_DAY.captures_iter(text) {
+        dates.extend(date(year(&c[3]), month(&c[1]), &c[2]).map(|d| (c.get(0).map_or(0, |m| m.start()), d)));
+    }
+    dates
+}
+
+// A document can not be dated in the future or before MIN_YEAR.
+pub fn plausible(date: NaiveDate) -> bool {
+    date <= Utc::now().date_naive() && date.year() >= MIN_YEAR
+}
+
+// Find the date of a document in its text: the first plausible date, as letters and invoices
+// are dated in their header.
+pub fn extract(text: &str) -> Option<NaiveDate> {
+    let mut dates = candidates(text);
+    dates.sort_by_key(|(position, _)| *position);
+    dates.into_iter()
+        .map(|(_, date)| date)
+        .find(|date| plausible(*date))
+}
+
+// A single date typed by a user: 2023-03-12, 12.03.2023 or 12 March 2023.
+pub fn parse(input: &str) -> Option<NaiveDate> {
+    let input = input.trim();
+    candidates(input).into_iter()
+        .find(|(position, _)| *position == 0)
+        .map(|(_, date)| date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(year: i32, month: u32, day: u32) -> Option<NaiveDate> {
+        NaiveDate::from_ymd_opt(year, month, day)
+    }
+
+    #[test]
+    fn parse_formats() {
+        assert_eq!(parse("2023-03-12"), day(2023, 3, 12));
+        assert_eq!(parse(" 12.03.2023 "), day(2023, 3, 12));
+        assert_eq!(parse("12.3.23"), day(2023, 3, 12));
+        assert_eq!(parse("12/03/2023"), day(2023, 3, 12));
+        assert_eq!(parse("03/25/2023"), day(2023, 3, 25));
+        assert_eq!(parse("12. Maerz 2023"), day(2023, 3, 12));
+        assert_eq!(parse("12. März 2023"), day(2023, 3, 12));
+        assert_eq!(parse("MÄRZ 12, 2023"), day(2023, 3, 12));
+        assert_eq!(parse("12th of March, 2023"), day(2023, 3, 12));
+        assert_eq!(parse("March 12, 2023"), day(2023, 3, 12));
+        assert_eq!(parse("1. Okt 2022"), day(2022, 10, 1));
+    }
+
+    #[test]
+    fn parse_two_digit_years_are_in_the_past() {
+        assert_eq!(parse("01.02.87"), day(1987, 2, 1));
+        assert_eq!(parse("01.02.05"), day(2005, 2, 1));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("yesterday"), None);
+        assert_eq!(parse("2023-13-01"), None);
+        assert_eq!(parse("31.02.2023"), None);
+        assert_eq!(parse("12 Foo 2023"), None);
+        // Only a date at the start of the input, not one somewhere in a sentence
+        assert_eq!(parse("due on 2023-03-12"), None);
+    }
+
+    #[test]
+    fn extract_first_plausible_date() {
+        let text = "Stadtwerke Rechnung vom 15.01.2023 fuer den Zeitraum 01.12.2022 bis 31.12.2022";
+        assert_eq!(extract(text), day(2023, 1, 15));
+    }
+
+    #[test]
+    fn extract_skips_implausible_dates() {
+        let text = "Geboren am 03.04.1850, Vertrag bis 31.12.2999, unterschrieben am 2021-06-30";
+        assert_eq!(extract(text), day(2021, 6, 30));
+    }
+
+    #[test]
+    fn extract_none() {
+        assert_eq!(extract("Kein Datum, nur Nummer 4711 und Betrag 12.50 EUR"), None);
+    }
+
+    #[test]
+    fn plausible_range() {
+        assert!(plausible(NaiveDate::from_ymd_opt(1900, 1, 1).unwrap()));
+        assert!(!plausible(NaiveDate::from_ymd_opt(1899, 12, 31).unwrap()));
+        assert!(!plausible(Utc::now().date_naive().succ_opt().unwrap()));
+    }
+}
@@ -0,0 +1,130 @@
+use crate::error::DmsError;
+use crate::llm;
+use crate::psql::{self, Database};
+use crate::settings::{Config, DocumentChunk, DocumentPage};
+
+// Words of a chunk. Embedding models only look at the first few hundred tokens of a text,
+// so a document is embedded in pieces.
+const CHUNK_WORDS: usize = 200;
+// Words repeated from the end of the previous chunk, so that a sentence is not cut in two.
+const CHUNK_OVERLAP: usize = 40;
+
+// Split the pages into overlapping chunks of words. Chunks do not span pages, so a hit can be shown with its page.
+pub fn chunks(pages: &[DocumentPage]) -> Vec<(i32, String)> {
+    let mut chunks = Vec::new();
+    for page in pages {
+        let words: Vec<&str> = page.content.split_whitespace().collect();
+        let mut start = 0;
+        while start < words.len() {
+            let end = (start + CHUNK_WORDS).min(words.len());
+            chunks.push((page.page, words[start..end].join(" ")));
+            if end == words.len() {
+                break;
+            }
+            start = end - CHUNK_OVERLAP;
+        }
+    }
+    chunks
+}
+
+// Embed every chunk of the pages with the model.
+pub async fn embed_pages(config: &Config, pages: &[DocumentPage], model: &str) -> Result<Vec<DocumentChunk>, DmsError> {
+    let mut embedded = Vec::new();
+    for (i, (page, content)) in chunks(pages).into_iter().enumerate() {
+        let embedding = llm::embedding(config, content.clone(), model).await?;
+        embedded.push(DocumentChunk {
+            _id: 0,
+            chunk: i as i32 + 1,
+            page,
+            content,
+            model: model.to_string(),
+            embedding,
+        });
+    }
+    Ok(embedded)
+}
+
+// Embeddings of a new Document, None without embedding model.
+// They are optional for the consume, so a failing model only gives a warning and None as well.
+pub async fn for_consume(config: &Config, pages: &[DocumentPage]) -> Option<Vec<DocumentChunk>> {
+    let model = config.embedding_model.as_ref()?;
+    match embed_pages(config, pages, model).await {
+        Ok(chunks) => Some(chunks),
+        Err(e) => {
+            eprintln!("No embeddings stored, run `dmslite embed` later: {}", e);
+            None
+        }
+    }
+}
+
+// Embed all Documents not embedded with the configured model yet,
+// e.g. those consumed before the model was set or changed.
+// Documents without any text get no chunks, but count as embedded as well.
+pub async fn embed_missing(config: &Config, db: &Database) -> Result<(), DmsError> {
+    let model = config.embedding_model.as_deref().ok_or(DmsError::NoEmbeddingModel)?;
+    let ids = psql::ids_without_embeddings(db, model).await?;
+    if ids.is_empty() {
+        println!("All documents have embeddings of {}", model);
+    }
+    for id in ids {
+        let pages = psql::pages(db, id).await?;
+        let chunks = embed_pages(config, &pages, model).await?;
+        psql::replace_chunks(db, id, model, &chunks).await?;
+        println!("Embedded document {} ({} chunks)", id, chunks.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(page: i32, words: usize) -> DocumentPage {
+        let content = (1..=words).map(|i| format!("w{}", i)).collect::<Vec<_>>().join(" ");
+        DocumentPage { _id: 0, page, content, method: "text_layer".to_string() }
+    }
+
+    fn words(chunk: &str) -> Vec<&str> {
+        chunk.split_whitespace().collect()
+    }
+
+    #[test]
+    fn short_page_is_one_chunk() {
+        let chunks = chunks(&[page(1, 5)]);
+        assert_eq!(chunks, vec![(1, "w1 w2 w3 w4 w5".to_string())]);
+    }
+
+    #[test]
+    fn empty_pages_have_no_chunks() {
+        assert!(chunks(&[page(1, 0), page(2, 0)]).is_empty());
+        assert!(chunks(&[]).is_empty());
+    }
+
+    #[test]
+    fn chunks_overlap() {
+        let chunks = chunks(&[page(1, 450)]);
+        assert_eq!(chunks.len(), 3);
+        let first = words(&chunks[0].1);
+        let second = words(&chunks[1].1);
+        let third = words(&chunks[2].1);
+        assert_eq!(first.len(), CHUNK_WORDS);
+        assert_eq!(second.len(), CHUNK_WORDS);
+        assert_eq!(first[CHUNK_WORDS - CHUNK_OVERLAP..], second[..CHUNK_OVERLAP]);
+        assert_eq!(second[CHUNK_WORDS - CHUNK_OVERLAP..], third[..CHUNK_OVERLAP]);
+        assert_eq!(third.last(), Some(&"w450"));
+    }
+
+    #[test]
+    fn page_filling_exactly_one_chunk() {
+        assert_eq!(chunks(&[page(1, CHUNK_WORDS)]).len(), 1);
+    }
+
+    #[test]
+    fn chunks_do_not_span_pages() {
+        let chunks = chunks(&[page(1, 3), page(2, 0), page(3, 250)]);
+        let pages: Vec<i32> = chunks.iter().map(|(page, _)| *page).collect();
+        assert_eq!(pages, vec![1, 3, 3]);
+        assert_eq!(chunks[0].1, "w1 w2 w3");
+        assert!(chunks[1].1.starts_with("w1 "));
+    }
+}
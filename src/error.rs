@@ -0,0 +1,107 @@
+use std::io;
+use std::path::PathBuf;
+
+use crate::psql::DbError;
+use crate::settings::ConfigError;
+
+// Errors of the DMSLite commands and the consume pipeline.
+// Every pipeline stage has its own variant, so a failed document reports exactly which stage failed.
+#[derive(Debug, thiserror::Error)]
+pub enum DmsError {
+    #[error("Cant execute {program}: {source}")]
+    Spawn { program: &'static str, source: io::Error },
+    #[error("Error converting {name} to jpg: {reason}")]
+    Pdf2Jpg { name: String, reason: String },
+    #[error("Unsupported file type: {0}")]
+    Unsupported(String),
+    #[error("pdftoppm produced no pages for {0}")]
+    NoPages(String),
+    #[error("Error using tesseract on {image}: {reason}")]
+    Ocr { image: String, reason: String },
+    #[error("Error creating the searchable PDF of {name}: {reason}")]
+    Archive { name: String, reason: String },
+    #[error("LLM {model} failed: {reason}")]
+    Llm { model: String, reason: String },
+    #[error("Cant move {from:?} to {to:?}: {source}")]
+    Store { from: PathBuf, to: PathBuf, source: io::Error },
+    #[error("{context}: {source}")]
+    Io { context: String, source: io::Error },
+    #[error(transparent)]
+    Db(#[from] DbError),
+    #[error("Configuration error: {0}")]
+    Config(#[from] ConfigError),
+    #[error("{0} document(s) could not be consumed")]
+    ConsumeFailed(usize),
+    #[error("Invalid id: {0:?}")]
+    InvalidId(String),
+    #[error("No document with id {0}")]
+    NotFound(i32),
+    #[error("No tag named {0:?}")]
+    TagNotFound(String),
+    #[error("Tag {0:?} already exists, merge the tags instead")]
+    TagExists(String),
+    #[error("Invalid date: {0:?}")]
+    InvalidDate(String),
+    #[error("Invalid date in the filter {prefix}:{value}")]
+    InvalidFilter { prefix: String, value: String },
+    #[error("Invalid correspondent name: {0:?}")]
+    InvalidCorrespondent(String),
+    #[error("Document {0} has no correspondent")]
+    NoCorrespondent(i32),
+    #[error("The original of document {0} was replaced by its searchable PDF")]
+    NoOriginal(i32),
+    #[error("Invalid command: {0:?}")]
+    InvalidCommand(String),
+    #[error("Empty search query")]
+    EmptyQuery,
+    #[error("The semantic search needs an embedding model, set embedding_model in the config")]
+    NoEmbeddingModel,
+    #[error("Error executing xdg-open: {0}")]
+    Open(String),
+}
+
+impl DmsError {
+    // For map_err: wrap an io::Error with a description of what was attempted.
+    pub fn io(context: impl Into<String>) -> impl FnOnce(io::Error) -> DmsError {
+        let context = context.into();
+        move |source| DmsError::Io { context, source }
+    }
+
+    // Whether the document itself made the consume pipeline fail, so consuming it again fails the same way.
+    // Errors of the environment (a missing program, the file system, no connection to Postgres) are not.
+    pub fn is_caused_by_document(&self) -> bool {
+        match self {
+            DmsError::Pdf2Jpg { .. } | DmsError::Unsupported(_) | DmsError::NoPages(_) | DmsError::Ocr { .. }
+                | DmsError::Archive { .. } | DmsError::Llm { .. } => true,
+            // Postgres rejected the data of the document, e.g. a value out of range.
+            DmsError::Db(DbError::Postgres(e)) => e.as_db_error().is_some(),
+            _ => false,
+        }
+    }
+}
+
+impl From<tokio_postgres::Error> for DmsError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        DmsError::Db(DbError::Postgres(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deadpool_postgres::{PoolError, TimeoutType};
+
+    #[test]
+    fn document_failures() {
+        assert!(DmsError::Ocr { image: "a.jpg".into(), reason: "exit status 1".into() }.is_caused_by_document());
+        assert!(DmsError::Llm { model: "llama3".into(), reason: "no JSON".into() }.is_caused_by_document());
+        assert!(DmsError::NoPages("a.pdf".into()).is_caused_by_document());
+    }
+
+    #[test]
+    fn connection_failures() {
+        assert!(!DmsError::Db(DbError::Pool(PoolError::Timeout(TimeoutType::Create))).is_caused_by_document());
+        assert!(!DmsError::Db(DbError::Pool(PoolError::Closed)).is_caused_by_document());
+        assert!(!DmsError::Spawn { program: "tesseract", source: io::ErrorKind::NotFound.into() }.is_caused_by_document());
+    }
+}
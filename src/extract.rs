@@ -0,0 +1,217 @@
+use chrono::NaiveDate;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::dates;
+use crate::settings::DocumentAmount;
+
+// Longer titles are cut.
+const MAX_TITLE_CHARS: usize = 120;
+// Longer answers are sentences instead of names.
+const MAX_CORRESPONDENT_CHARS: usize = 80;
+
+// Metadata of a document generated by the LLM.
+// The separate requests only give the title, the summary and the buzzwords.
+pub struct Extraction {
+    pub title: String,
+    pub summary: String,
+    // Keywords separated by commas
+    pub buzzwords: String,
+    pub document_type: Option<String>,
+    pub date: Option<NaiveDate>,
+    pub correspondent: Option<String>,
+    pub amounts: Vec<DocumentAmount>,
+}
+
+// The answer as the schema describes it, unknown values are empty strings
+// since not every backend supports nullable types in the schema.
+#[derive(Deserialize)]
+struct Answer {
+    title: String,
+    summary: String,
+    #[serde(default)]
+    keywords: Vec<String>,
+    #[serde(default)]
+    document_type: String,
+    #[serde(default)]
+    date: String,
+    #[serde(default)]
+    correspondent: String,
+    #[serde(default)]
+    amounts: Vec<AnswerAmount>,
+}
+
+#[derive(Deserialize)]
+struct AnswerAmount {
+    value: f64,
+    currency: String,
+}
+
+// JSON schema of the answer, sent with the request so the model can only answer with such an object.
+pub fn schema() -> serde_json::Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "title": { "type": "string" },
+            "summary": { "type": "string" },
+            "keywords": { "type": "array", "items": { "type": "string" } },
+            "document_type": { "type": "string" },
+            "date": { "type": "string" },
+            "correspondent": { "type": "string" },
+            "amounts": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "value": { "type": "number" },
+                        "currency": { "type": "string" },
+                    },
+                    "required": ["value", "currency"],
+                },
+            },
+        },
+        "required": ["title", "summary", "keywords", "document_type", "date", "correspondent", "amounts"],
+    })
+}
+
+// Name of the sender as answered by the LLM, None if the model found none.
+pub fn correspondent_name(answer: &str) -> Option<String> {
+    let name = answer.split_whitespace().collect::<Vec<_>>().join(" ");
+    let name = name.trim_matches(|c: char| c == '*' || c == '"' || c == '.' || c.is_whitespace());
+    match name.to_lowercase().as_str() {
+        "" | "unbekannt" | "unknown" | "null" | "none" => None,
+        _ if name.chars().count() > MAX_CORRESPONDENT_CHARS => None,
+        _ => Some(name.to_string()),
+    }
+}
+
+// Optional text of the answer, empty or "unknown" values are None.
+fn optional(value: &str) -> Option<String> {
+    let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+    match value.to_lowercase().as_str() {
+        "" | "unbekannt" | "unknown" | "null" | "none" => None,
+        _ => Some(value),
+    }
+}
+
+// Parse and validate the answer of the LLM.
+// Models may wrap the object in a Markdown code block, so only the text from the first { to the last } is read.
+// The error tells why the answer is invalid, so that the request can be retried.
+pub fn parse(answer: &str) -> Result<Extraction, String> {
+    let (Some(start), Some(end)) = (answer.find('{'), answer.rfind('}')) else {
+        return Err("no JSON object in the answer".to_string());
+    };
+    if end < start {
+        return Err("no JSON object in the answer".to_string());
+    }
+    let answer: Answer = serde_json::from_str(&answer[start..=end]).map_err(|e| e.to_string())?;
+
+    let title: String = answer.title.split_whitespace().collect::<Vec<_>>().join("_").replace('*', "")
+        .chars().take(MAX_TITLE_CHARS).collect();
+    if title.is_empty() {
+        return Err("empty title".to_string());
+    }
+    let summary = answer.summary.split_whitespace().collect::<Vec<_>>().join(" ");
+    if summary.is_empty() {
+        return Err("empty summary".to_string());
+    }
+
+    let mut keywords: Vec<String> = Vec::new();
+    for keyword in answer.keywords.iter().filter_map(|keyword| optional(keyword)) {
+        if !keywords.contains(&keyword) {
+            keywords.push(keyword);
+        }
+    }
+
+    // Models make up dates, so only plausible ones are taken like in dates::extract.
+    let date = dates::parse(&answer.date).filter(|date| dates::plausible(*date));
+
+    let amounts = answer.amounts.into_iter()
+        .filter(|amount| amount.value.is_finite())
+        .filter_map(|amount| optional(&amount.currency).map(|currency| (amount.value, currency.to_uppercase())))
+        .enumerate()
+        .map(|(i, (value, currency))| DocumentAmount { _id: 0, position: i as i32 + 1, value, currency })
+        .collect();
+
+    Ok(Extraction {
+        title,
+        summary,
+        buzzwords: keywords.join(", "),
+        document_type: optional(&answer.document_type),
+        date,
+        correspondent: correspondent_name(&answer.correspondent),
+        amounts,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_complete_answer() {
+        let answer = r#"{"title": "Stromrechnung  Januar", "summary": " Rechnung der\nStadtwerke ", "keywords": ["Strom", "Rechnung", "Strom", "unknown"],
+            "document_type": "Rechnung", "date": "2023-01-15", "correspondent": "Stadtwerke Köln",
+            "amounts": [{"value": 84.5, "currency": "eur"}, {"value": 10, "currency": ""}]}"#;
+        let extraction = parse(answer).unwrap();
+        assert_eq!(extraction.title, "Stromrechnung_Januar");
+        assert_eq!(extraction.summary, "Rechnung der Stadtwerke");
+        assert_eq!(extraction.buzzwords, "Strom, Rechnung");
+        assert_eq!(extraction.document_type.as_deref(), Some("Rechnung"));
+        assert_eq!(extraction.date, NaiveDate::from_ymd_opt(2023, 1, 15));
+        assert_eq!(extraction.correspondent.as_deref(), Some("Stadtwerke Köln"));
+        assert_eq!(extraction.amounts.len(), 1);
+        assert_eq!((extraction.amounts[0].position, extraction.amounts[0].value), (1, 84.5));
+        assert_eq!(extraction.amounts[0].currency, "EUR");
+    }
+
+    #[test]
+    fn parse_code_block() {
+        let answer = "Here is the JSON:\n```json\n{\"title\": \"Vertrag\", \"summary\": \"Mietvertrag\"}\n```";
+        let extraction = parse(answer).unwrap();
+        assert_eq!(extraction.title, "Vertrag");
+        assert_eq!(extraction.buzzwords, "");
+        assert!(extraction.amounts.is_empty());
+    }
+
+    #[test]
+    fn parse_unknown_values() {
+        let answer = r#"{"title": "**Brief**", "summary": "Ein Brief", "document_type": "unknown", "date": "", "correspondent": "Unbekannt"}"#;
+        let extraction = parse(answer).unwrap();
+        assert_eq!(extraction.title, "Brief");
+        assert_eq!(extraction.document_type, None);
+        assert_eq!(extraction.date, None);
+        assert_eq!(extraction.correspondent, None);
+    }
+
+    #[test]
+    fn parse_implausible_date() {
+        let answer = r#"{"title": "Brief", "summary": "Ein Brief", "date": "2999-01-01"}"#;
+        assert_eq!(parse(answer).unwrap().date, None);
+    }
+
+    #[test]
+    fn parse_long_title() {
+        let answer = format!(r#"{{"title": "{}", "summary": "Ein Brief"}}"#, "ä".repeat(200));
+        assert_eq!(parse(&answer).unwrap().title.chars().count(), MAX_TITLE_CHARS);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(parse("no json here").err().unwrap(), "no JSON object in the answer");
+        assert_eq!(parse("} {").err().unwrap(), "no JSON object in the answer");
+        assert!(parse(r#"{"title": "Brief""#).is_err());
+        // Missing required fields
+        assert!(parse(r#"{"title": "Brief"}"#).is_err());
+        assert!(parse(r#"{"title": "Brief", "summary": 3}"#).is_err());
+        assert_eq!(parse(r#"{"title": " ** ", "summary": "Ein Brief"}"#).err().unwrap(), "empty title");
+        assert_eq!(parse(r#"{"title": "Brief", "summary": "  "}"#).err().unwrap(), "empty summary");
+    }
+
+    #[test]
+    fn correspondent_names() {
+        assert_eq!(correspondent_name(" **\"Stadtwerke  Köln\".** ").as_deref(), Some("Stadtwerke Köln"));
+        assert_eq!(correspondent_name("None"), None);
+        assert_eq!(correspondent_name(&"word ".repeat(30)), None);
+    }
+}
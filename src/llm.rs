@@ -1,8 +1,9 @@
-use ollama_rs::generation::completion::request::GenerationRequest;
-use ollama_rs::Ollama;
-use std::fs;
-
-use crate::settings::settings;
+use crate::backend::{Backend, LlmBackend, LlmRequest};
+use crate::error::DmsError;
+use crate::extract::{self, Extraction};
+use crate::prompts::{self, Prompts};
+use crate::settings::{Config, LlmConfig, LlmTaskConfig};
+use crate::tokens;
 
 // Remove chain of more then one whitespace char to only one.
 // E.g. "text  \n   text" to "text text"
@@ -17,39 +18,355 @@ fn tidy_up_string(mut string: String) -> String {
     string
 }
 
-//  prompt all LLMs over the document context that is saved in the generated output.txt file from Tesseract.
-pub async fn llm_prompt() -> (String, String, String, String) {
-    let mut contents = fs::read_to_string(settings::CONSUME_PATH.to_owned()+"output.txt")
-        .expect("Should have been able to read the file");
-    contents.retain(|c| c.is_ascii());
-    contents.retain(|c| !c.is_ascii_control());
-    contents = tidy_up_string(contents);
-    // println!("{}",contents);
+// Bring the text of a page onto a single line: line breaks and other whitespace become single spaces,
+// control chars are dropped. Umlauts and any other Unicode text are kept.
+pub fn clean_ocr_text(contents: String) -> String {
+    let contents: String = contents.chars()
+        .map(|c| if c.is_whitespace() { ' ' } else { c })
+        .filter(|c| !c.is_control())
+        .collect();
+    tidy_up_string(contents)
+}
+
+// Requests of the JSON extraction before giving up on invalid answers.
+const EXTRACT_ATTEMPTS: usize = 3;
+// Context window of models without a configured one, the Ollama default.
+const DEFAULT_CONTEXT_WINDOW: u32 = 2048;
+// Tokens of the context window kept free for the answer.
+const ANSWER_TOKENS: usize = 512;
+// Smallest prompt, for tiny context windows or very long system prompts.
+const MIN_PROMPT_TOKENS: usize = 256;
+
+// The tasks of the LLM, each with its own model, temperature and system prompt.
+#[derive(Debug, Clone, Copy)]
+pub enum Task {
+    Extract,
+    Chunk,
+    Summary,
+    Buzzwords,
+    Title,
+    Correspondent,
+    Answer,
+}
+
+impl Task {
+    // Custom model of the Modelfile shipped with DMSLite.
+    fn default_model(&self) -> &'static str {
+        match self {
+            Task::Extract => "doc_extractor",
+            Task::Chunk => "doc_summarizer",
+            Task::Summary => "doc_summarizer",
+            Task::Buzzwords => "doc_buzzword_generator",
+            Task::Title => "doc_title_generator",
+            Task::Correspondent => "doc_correspondent_generator",
+            Task::Answer => "doc_answerer",
+        }
+    }
+
+    // Temperature of the Modelfile.
+    fn default_temperature(&self) -> f32 {
+        match self {
+            Task::Extract | Task::Buzzwords | Task::Correspondent => 0.1,
+            Task::Chunk | Task::Summary | Task::Title | Task::Answer => 0.2,
+        }
+    }
+
+    fn system(&self, prompts: &'static Prompts) -> &'static str {
+        match self {
+            Task::Extract => prompts.extract,
+            Task::Chunk => prompts.chunk,
+            Task::Summary => prompts.summary,
+            Task::Buzzwords => prompts.buzzwords,
+            Task::Title => prompts.title,
+            Task::Correspondent => prompts.correspondent,
+            Task::Answer => prompts.answer,
+        }
+    }
 
+    fn config<'a>(&self, llm: &'a LlmConfig) -> &'a LlmTaskConfig {
+        match self {
+            Task::Extract => &llm.extract,
+            Task::Chunk => &llm.chunk,
+            Task::Summary => &llm.summary,
+            Task::Buzzwords => &llm.buzzwords,
+            Task::Title => &llm.title,
+            Task::Correspondent => &llm.correspondent,
+            Task::Answer => &llm.answer,
+        }
+    }
+}
 
-    let mut summary = llm_inference(contents.clone(), "doc_summarizer".to_string()).await;
-    let mut buzzwords = llm_inference(contents.clone(), "doc_buzzword_generator".to_string()).await;
-    let mut title = llm_inference(buzzwords.clone(), "doc_title_generator".to_string()).await;
+// Prompt set of the configured language, or of the Tesseract language.
+pub fn prompts(config: &Config) -> &'static Prompts {
+    prompts::for_language(config.llm.language.as_deref().unwrap_or(&config.tesseract_lang))
+}
+
+//  prompt all LLMs over the cleaned document content from Tesseract.
+// Fails if any of the LLMs fails or gives an empty answer.
+pub async fn llm_prompt(config: &Config, contents: String) -> Result<Extraction, DmsError> {
+    let backend = Backend::new(config)?;
+    let contents = condense(&backend, config, Task::Summary, contents).await?;
+    let mut summary = llm_inference(&backend, config, Task::Summary, contents.clone(), None).await?;
+    let contents = condense(&backend, config, Task::Buzzwords, contents).await?;
+    let mut buzzwords = llm_inference(&backend, config, Task::Buzzwords, contents, None).await?;
+    let mut title = llm_inference(&backend, config, Task::Title, buzzwords.clone(), None).await?;
 
     summary = tidy_up_string(summary);
     buzzwords = tidy_up_string(buzzwords);
     title = tidy_up_string(title);
-    title = title.replace("*", "");
+    title = title.replace('*', "");
+
+    Ok(Extraction { title, summary, buzzwords, document_type: None, date: None, correspondent: None, amounts: Vec::new() })
+}
+
+// Extract title, summary, keywords, document type, date, correspondent and amounts in a single request,
+// answered as JSON following extract::schema. Invalid answers are requested again, up to EXTRACT_ATTEMPTS times.
+pub async fn extract(config: &Config, contents: String) -> Result<Extraction, DmsError> {
+    extract_with(&Backend::new(config)?, config, contents).await
+}
 
-    (contents, summary, buzzwords, title)
+// extract with the given backend instead of the configured one.
+async fn extract_with(backend: &impl LlmBackend, config: &Config, contents: String) -> Result<Extraction, DmsError> {
+    let schema = extract::schema();
+    let contents = condense(backend, config, Task::Extract, contents).await?;
+    let mut reason = String::new();
+    for attempt in 1..=EXTRACT_ATTEMPTS {
+        let answer = llm_inference(backend, config, Task::Extract, contents.clone(), Some(&schema)).await?;
+        match extract::parse(&answer) {
+            Ok(extraction) => return Ok(extraction),
+            Err(e) => {
+                eprintln!("Invalid answer of the LLM (attempt {} of {}): {}", attempt, EXTRACT_ATTEMPTS, e);
+                reason = e;
+            }
+        }
+    }
+    Err(DmsError::Llm { model: task_model(config, Task::Extract), reason: format!("invalid JSON answer: {}", reason) })
 }
 
-// Generate Answer for a LLM with User Input (ocr).
-async fn llm_inference(ocr: String, model: String) -> String{
-    let ollama = Ollama::default();
-    let model = model;
-    let prompt = ocr;
-    
-    let res = ollama.generate(GenerationRequest::new(model, prompt)).await;
-    
-    if let Ok(res) = res {
-        return res.response;
-    } else {
-        return "".to_string();
+// Ask the LLM for the sender of the document.
+// The correspondent is optional, so a failing or missing model only gives a warning.
+pub async fn propose_correspondent(config: &Config, contents: String) -> Option<String> {
+    // The sender is named in the letterhead, so long documents are cut instead of summarized.
+    let contents = beginning(config, Task::Correspondent, &contents);
+    let answer = match Backend::new(config) {
+        Ok(backend) => llm_inference(&backend, config, Task::Correspondent, contents, None).await,
+        Err(e) => Err(e.into()),
+    };
+    let answer = match answer {
+        Ok(answer) => answer,
+        Err(e) => {
+            eprintln!("No correspondent proposed: {}", e);
+            return None;
+        }
+    };
+    extract::correspondent_name(&answer)
+}
+
+// Answer the question in the prompt from the document excerpts in it, citing them as [id:page].
+pub async fn answer(backend: &impl LlmBackend, config: &Config, prompt: String) -> Result<String, DmsError> {
+    let answer = llm_inference(backend, config, Task::Answer, prompt, None).await?;
+    Ok(answer.trim().to_string())
+}
+
+// Embedding vector of the text, computed by the embedding model.
+pub async fn embedding(config: &Config, text: String, model: &str) -> Result<Vec<f32>, DmsError> {
+    let embedding = Backend::new(config)?.embed(model, &text).await?;
+
+    if embedding.is_empty() {
+        return Err(DmsError::Llm { model: model.to_string(), reason: "empty embedding".to_string() });
     }
-}
\ No newline at end of file
+    Ok(embedding)
+}
+
+// Model of the task from the [llm.<task>] settings, then the [llm] model and the default of the task.
+fn task_model(config: &Config, task: Task) -> String {
+    task.config(&config.llm).model.clone().or(config.llm.model.clone()).unwrap_or(task.default_model().to_string())
+}
+
+// System prompt of the task from the [llm.<task>] settings, then the one of the prompt set.
+fn task_system(config: &Config, task: Task) -> String {
+    task.config(&config.llm).system.clone().unwrap_or(task.system(prompts(config)).to_string())
+}
+
+// Context window of the model from [llm.context_windows], then the [llm] context_window.
+fn context_window(config: &Config, model: &str) -> Option<u32> {
+    config.llm.context_windows.get(model).copied().or(config.llm.context_window)
+}
+
+// Tokens of text the task can be given: the context window of its model without the system prompt and the answer.
+pub fn prompt_budget(config: &Config, task: Task) -> usize {
+    let window = context_window(config, &task_model(config, task)).unwrap_or(DEFAULT_CONTEXT_WINDOW) as usize;
+    let system = tokens::estimate(&task_system(config, task));
+    window.saturating_sub(system + ANSWER_TOKENS).max(MIN_PROMPT_TOKENS)
+}
+
+// The beginning of the text that fits into the context window of the model of the task.
+fn beginning(config: &Config, task: Task, text: &str) -> String {
+    tokens::split(text, prompt_budget(config, task)).into_iter().next().unwrap_or_default()
+}
+
+// Shorten a document too long for the context window of the model of the task (map-reduce):
+// it is split into chunks that fit the context window of the chunk model, every chunk is summarized
+// and the summaries are joined. Summaries still too long are summarized again the same way.
+// If a round does not shorten the text anymore, the rest that does not fit is cut.
+async fn condense(backend: &impl LlmBackend, config: &Config, task: Task, mut text: String) -> Result<String, DmsError> {
+    let budget = prompt_budget(config, task);
+    let mut round = 0;
+    loop {
+        let tokens = tokens::estimate(&text);
+        if tokens <= budget {
+            return Ok(text);
+        }
+        round += 1;
+        let chunks = tokens::split(&text, prompt_budget(config, Task::Chunk));
+        println!("Summarizing {} chunks of the document (about {} tokens, round {})", chunks.len(), tokens, round);
+        let mut summaries = Vec::new();
+        for chunk in chunks {
+            summaries.push(tidy_up_string(llm_inference(backend, config, Task::Chunk, chunk, None).await?));
+        }
+        let condensed = summaries.join("\n");
+        if tokens::estimate(&condensed) >= tokens {
+            eprintln!("The document is too long for the context window of {}, the rest is cut", task_model(config, task));
+            return Ok(beginning(config, task, &text));
+        }
+        text = condensed;
+    }
+}
+
+// Generate Answer for a LLM with User Input (ocr), following the JSON schema <format> if given.
+// Temperature and system prompt come from the [llm.<task>] settings, then the defaults of the task
+// with the system prompt in the language of the prompt set.
+async fn llm_inference(backend: &impl LlmBackend, config: &Config, task: Task, ocr: String, format: Option<&serde_json::Value>) -> Result<String, DmsError> {
+    let own = task.config(&config.llm);
+    let model = task_model(config, task);
+    let temperature = own.temperature.unwrap_or(task.default_temperature());
+    let system = task_system(config, task);
+    let context_window = context_window(config, &model);
+    let request = LlmRequest { model, system, temperature, prompt: ocr, format: format.cloned(), context_window };
+
+    let response = backend.generate(&request).await?;
+
+    if response.trim().is_empty() {
+        return Err(DmsError::Llm { model: request.model, reason: "empty response".to_string() });
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MockBackend;
+
+    const VALID: &str = r#"{"title": "Stromrechnung", "summary": "Rechnung der Stadtwerke", "keywords": ["Strom"],
+        "document_type": "Rechnung", "date": "2023-01-15", "correspondent": "Stadtwerke", "amounts": []}"#;
+
+    #[test]
+    fn clean_ocr_text_keeps_unicode() {
+        assert_eq!(clean_ocr_text("Größe:\tÄpfel  für\n\nMüller\x0c".to_string()), "Größe: Äpfel für Müller");
+        assert_eq!(clean_ocr_text("Rech\u{7}nung\r\n1\u{a0}234,56 €".to_string()), "Rechnung 1 234,56 €");
+        assert_eq!(clean_ocr_text(" \n ".to_string()), "");
+    }
+
+    #[tokio::test]
+    async fn extract_first_answer() {
+        let mock = MockBackend::with_answers(&[VALID]);
+        let extraction = extract_with(&mock, &Config::default(), "Rechnung".to_string()).await.unwrap();
+        assert_eq!(extraction.title, "Stromrechnung");
+        assert_eq!(mock.prompts(), vec!["Rechnung"]);
+    }
+
+    #[tokio::test]
+    async fn extract_retries_invalid_answers() {
+        let mock = MockBackend::with_answers(&["Sure! Here is the summary.", r#"{"title": "Strom", "summary": ""}"#, VALID]);
+        let extraction = extract_with(&mock, &Config::default(), "Rechnung".to_string()).await.unwrap();
+        assert_eq!(extraction.summary, "Rechnung der Stadtwerke");
+        assert_eq!(mock.prompts().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn extract_gives_up() {
+        let mock = MockBackend::with_answers(&["no json", "{}", r#"{"title": "Strom", "summary": " "}"#, VALID]);
+        let result = extract_with(&mock, &Config::default(), "Rechnung".to_string()).await;
+        match result {
+            Err(DmsError::Llm { model, reason }) => {
+                assert_eq!(model, "doc_extractor");
+                assert_eq!(reason, "invalid JSON answer: empty summary");
+            }
+            _ => panic!("extraction did not fail"),
+        }
+        assert_eq!(mock.prompts().len(), EXTRACT_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn empty_answer_is_an_error() {
+        let mock = MockBackend::with_answers(&["  \n"]);
+        assert!(matches!(answer(&mock, &Config::default(), "Frage".to_string()).await, Err(DmsError::Llm { .. })));
+    }
+
+    #[test]
+    fn prompt_budget_of_the_context_window() {
+        let mut config = Config::default();
+        let system = tokens::estimate(&task_system(&config, Task::Answer));
+        assert_eq!(prompt_budget(&config, Task::Answer), DEFAULT_CONTEXT_WINDOW as usize - system - ANSWER_TOKENS);
+        config.llm.context_windows.insert("doc_answerer".to_string(), 8192);
+        assert_eq!(prompt_budget(&config, Task::Answer), 8192 - system - ANSWER_TOKENS);
+        config.llm.context_windows.insert("doc_answerer".to_string(), 512);
+        assert_eq!(prompt_budget(&config, Task::Answer), MIN_PROMPT_TOKENS);
+    }
+
+    // Config with the smallest prompt budget for extraction and chunk summaries.
+    fn small_windows() -> Config {
+        let mut config = Config::default();
+        for model in ["doc_extractor", "doc_summarizer"] {
+            config.llm.context_windows.insert(model.to_string(), 512);
+        }
+        assert_eq!(prompt_budget(&config, Task::Extract), MIN_PROMPT_TOKENS);
+        assert_eq!(prompt_budget(&config, Task::Chunk), MIN_PROMPT_TOKENS);
+        config
+    }
+
+    // Text of n words of a single token each.
+    fn words(n: usize) -> String {
+        vec!["ab"; n].join(" ")
+    }
+
+    #[tokio::test]
+    async fn condense_short_text() {
+        let mock = MockBackend::default();
+        let text = condense(&mock, &small_windows(), Task::Extract, words(200)).await.unwrap();
+        assert_eq!(text, words(200));
+        assert!(mock.prompts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn condense_summarizes_chunks() {
+        let mock = MockBackend::with_answers(&["Erster  Teil", "Zweiter Teil", "Dritter Teil"]);
+        let text = condense(&mock, &small_windows(), Task::Extract, words(600)).await.unwrap();
+        assert_eq!(text, "Erster Teil\nZweiter Teil\nDritter Teil");
+        let prompts = mock.prompts();
+        assert_eq!(prompts.len(), 3);
+        assert_eq!(prompts[0], words(MIN_PROMPT_TOKENS));
+        assert_eq!(prompts[2], words(600 - 2 * MIN_PROMPT_TOKENS));
+    }
+
+    #[tokio::test]
+    async fn condense_summaries_again() {
+        let long = words(150);
+        let mock = MockBackend::with_answers(&[&long, &long, &long, "Erste Hälfte", "Zweite Hälfte"]);
+        let text = condense(&mock, &small_windows(), Task::Extract, words(600)).await.unwrap();
+        assert_eq!(text, "Erste Hälfte\nZweite Hälfte");
+        let prompts = mock.prompts();
+        assert_eq!(prompts.len(), 5);
+        // The second round summarizes the joined summaries of the first
+        assert_eq!(tokens::estimate(&prompts[3]) + tokens::estimate(&prompts[4]), 450);
+    }
+
+    #[tokio::test]
+    async fn condense_cuts_the_rest() {
+        let longer = words(200);
+        let mock = MockBackend::with_answers(&[&longer, &longer]);
+        let text = condense(&mock, &small_windows(), Task::Extract, words(300)).await.unwrap();
+        assert_eq!(text, words(MIN_PROMPT_TOKENS));
+        assert_eq!(mock.prompts().len(), 2);
+    }
+}
@@ -1,15 +1,198 @@
 use std::process::Command;
 use std::fs;
-use chrono::{NaiveDate, Utc};
-use ids_service::crypto_hash::*;
+use chrono::Utc;
 use std::path::{Path, PathBuf};
-use std::io;
+use std::io::{self, IsTerminal};
+use std::process::ExitCode;
+use clap::{Args, Parser, Subcommand};
+use sha2::{Digest, Sha256};
 
 mod settings;
+mod error;
 mod ocr;
 mod llm;
 mod psql;
+mod query;
+mod migrations;
+mod watch;
+mod tags;
+mod correspondents;
+mod dates;
+mod embeddings;
+mod ask;
+mod prompts;
+mod backend;
+#[cfg(test)]
+mod stub_server;
+mod extract;
+mod tokens;
+use crate::error::DmsError;
 use crate::psql::Database;
+use crate::query::{DateField, Order, SearchMode, SearchOptions, Sort};
+use crate::settings::{ArchiveMode, Config, ConfigArgs, DuplicateMode, ExtractionMode};
+
+// Consumed Docs with at least this trigram similarity to a stored Doc are reported as near-duplicates.
+const NEAR_DUPLICATE_SIMILARITY: f32 = 0.9;
+// Folder inside the consume dir for files that could not be consumed. Not watched, so they are not retried in a loop.
+const FAILED_DIR: &str = "failed";
+// Folder inside the consume dir for skipped duplicates, so that a wrong match does not lose the file.
+const DUPLICATES_DIR: &str = "duplicates";
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(flatten)]
+    config: ConfigArgs,
+    // Without a subcommand the interactive shell is started.
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Consume all documents in the consume folder
+    Consume,
+    /// Keep running and consume every new document in the consume folder
+    Watch,
+    /// Compute the embeddings for the semantic search of all documents without embeddings of the configured model
+    Embed,
+    /// Fuzzy search documents by content, summary and buzzwords.
+    /// Supports "quoted phrases", +required and -excluded terms, title:/content:/summary:/buzzwords: prefixes
+    /// and tag:name, from:name, date:2023, after:2023-01-01, before:2023-01-01 and year:2023 filters
+    Search {
+        #[command(flatten)]
+        results: ResultArgs,
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        query: Vec<String>,
+    },
+    /// Answer a question from the stored documents with the local LLM, citing the documents and pages
+    Ask {
+        #[arg(required = true, trailing_var_arg = true)]
+        question: Vec<String>,
+    },
+    /// Open a document with its default application. Opens the searchable PDF if there is one
+    Open {
+        id: i32,
+        /// Open the original file instead of the searchable PDF
+        #[arg(long)]
+        original: bool,
+    },
+    /// Delete a document from the database and the storage folder
+    Delete { id: i32 },
+    /// List all documents, newest first. Takes the same filters as search, e.g. from:name or year:2023
+    List {
+        #[command(flatten)]
+        results: ResultArgs,
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        filters: Vec<String>,
+    },
+    /// Correct the date of a document (e.g. 2023-03-12 or 12.03.2023). Without a date it is removed
+    Date { id: i32, date: Option<String> },
+    /// Start the interactive shell
+    Shell,
+    /// Manage the tags of documents
+    Tag {
+        #[command(subcommand)]
+        command: TagCommands,
+    },
+    /// Manage the senders of documents
+    Correspondent {
+        #[command(subcommand)]
+        command: CorrespondentCommands,
+    },
+    /// Manage the database schema
+    Db {
+        #[command(subcommand)]
+        command: DbCommands,
+    },
+}
+
+// Presentation of the results of search and list.
+#[derive(Args)]
+struct ResultArgs {
+    /// How the search terms are matched: fuzzy (trigram), fulltext (stemmed words), combined,
+    /// semantic (embeddings) or hybrid (fuzzy and semantic)
+    #[arg(long, value_enum, default_value_t)]
+    mode: SearchMode,
+    /// Date to show, to sort by and to filter by with date:, after:, before: and year:
+    #[arg(long, value_enum, default_value_t)]
+    date: DateField,
+    /// Sort the results by [default: rank for search, date for list]
+    #[arg(long, value_enum)]
+    sort: Option<Sort>,
+    /// Sort ascending or descending [default: best rank, newest date, title from A, lowest id first]
+    #[arg(long, value_enum)]
+    order: Option<Order>,
+    /// Number of results to show, 0 shows all
+    #[arg(long, default_value_t = query::DEFAULT_LIMIT)]
+    limit: u32,
+    /// Number of results to skip
+    #[arg(long, default_value_t = 0)]
+    offset: u32,
+}
+
+impl ResultArgs {
+    fn options(&self, default_sort: Sort, fts_config: &'static str) -> SearchOptions {
+        let sort = self.sort.unwrap_or(default_sort);
+        SearchOptions {
+            mode: self.mode,
+            fts_config,
+            embedding: None,
+            hybrid_max_distance: query::DEFAULT_HYBRID_MAX_DISTANCE,
+            date: self.date,
+            sort,
+            order: self.order.unwrap_or(sort.default_order()),
+            limit: (self.limit > 0).then_some(self.limit),
+            offset: self.offset,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum TagCommands {
+    /// Add tags to a document. Confirms tags suggested from the buzzwords
+    Add {
+        id: i32,
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+    /// Remove tags from a document
+    Remove {
+        id: i32,
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+    /// Rename a tag
+    Rename { old: String, new: String },
+    /// Move all documents of a tag to another tag and delete it
+    Merge { from: String, into: String },
+    /// List all tags
+    List,
+}
+
+#[derive(Subcommand)]
+enum CorrespondentCommands {
+    /// Set the correspondent of a document, overriding the proposed one
+    Set {
+        id: i32,
+        #[arg(required = true)]
+        name: Vec<String>,
+    },
+    /// Confirm the proposed correspondent of a document
+    Confirm { id: i32 },
+    /// Remove the correspondent of a document
+    Unset { id: i32 },
+    /// List all correspondents
+    List,
+}
+
+#[derive(Subcommand)]
+enum DbCommands {
+    /// Create the schema in a new database and apply all migrations
+    Init,
+    /// Apply all pending migrations to an existing database
+    Migrate,
+}
 
 //Takes a Path and a filename and returns the the path to the changed File with its new name. 
 fn change_file_name(path: impl AsRef<Path>, name: &str) -> PathBuf {
@@ -22,336 +205,641 @@ fn change_file_name(path: impl AsRef<Path>, name: &str) -> PathBuf {
     result
 }
 
+// Everything stored for a single Doc.
+struct Entry {
+    document: settings::Document,
+    content: settings::DocumentContent,
+    pages: Vec<settings::DocumentPage>,
+    // Amounts of money found by the LLM
+    amounts: Vec<settings::DocumentAmount>,
+    // Searchable PDF inside the working dir, to be stored at document.archive_filepath
+    archive: Option<PathBuf>,
+    // Tags suggested from the buzzwords
+    tags: Vec<String>,
+    // Embedded chunks of the pages, empty without embedding model
+    chunks: Vec<settings::DocumentChunk>,
+}
+
 // Creates a Entry for the Postgres DB.
-// 1. Use OCR to get the content of the Doc. -> Saved in output.txt inside the consume folder.
-// 2. Use Ollama to generate summaries and classify the Doc based on its content.
-// 3. Generate the structs Document and Document_content
-// 4. Copy the document from the Consume folder into the long-time storage folder with a unique hash as the filename
-async fn create_entry(name: String) -> (settings::Document, settings::DocumentContent) {
+// 1. Use the PDF text layer or OCR to get the content of every page of the Doc.
+//    The OCR also creates the searchable PDF if enabled and any page had to be OCRed.
+// 2. Find the date of the Doc in its content.
+// 3. Use Ollama to generate summaries and classify the Doc based on its content, the buzzwords become suggested tags.
+// 4. Generate the structs Document, Document_content and one DocumentPage per page
+//    with the SHA-256 hash of the file as the filename inside the long-time storage folder.
+// Intermediate files are written into workdir.
+// Fails if OCR or any of the LLMs failed, the Doc is left untouched in the Consume folder then.
+async fn create_entry(config: &Config, name: String, hash: String, workdir: &Path) -> Result<Entry, DmsError> {
+    let path = config.consume_path.join(&name);
+
     // Read Content
-    ocr::ocr(name.clone());
+    let scan = ocr::ocr(config, &path, workdir, config.archive != ArchiveMode::Off)?;
+    let page_texts: Vec<ocr::PageText> = scan.pages
+        .into_iter()
+        .map(|page| ocr::PageText { text: llm::clean_ocr_text(page.text), method: page.method })
+        .collect();
+    let content = page_texts.iter().map(|page| page.text.as_str()).collect::<Vec<_>>().join(" ");
+    let archive = scan.archive;
 
     // Generate Texts
-    let (content, summary, buzzwords, title) = llm::llm_prompt().await;
-
-    // Copy File into Storage Dir with Hash as the name.
-    let hash = create_id_as_sha256();
-    let old_path = settings::settings::CONSUME_PATH;
-    let new_name = change_file_name(&name.clone(), &hash).into_os_string().into_string().unwrap();
-    let new_path = settings::settings::STORAGE_PATH.to_owned()+&new_name;
-    let _ = match fs::rename(old_path.to_owned()+&name, new_path.clone()) {
-        Ok(()) => (),
-        Err(e) => {
-            eprint!("Error: {}", e);
-        },
+    let extraction = match config.llm.extraction {
+        ExtractionMode::Json => llm::extract(config, content.clone()).await?,
+        ExtractionMode::Separate => llm::llm_prompt(config, content.clone()).await?,
+    };
+    let tags = tags::suggestions(&extraction.buzzwords);
+
+    // A date found in the text is more reliable than the one of the LLM.
+    let document_date = dates::extract(&content).or(extraction.date);
+    if let Some(date) = document_date {
+        println!("Document date: {}", date);
+    }
+    if let Some(document_type) = &extraction.document_type {
+        println!("Document type: {}", document_type);
+    }
+    for amount in &extraction.amounts {
+        println!("Amount: {:.2} {}", amount.value, amount.currency);
+    }
+
+    // Storage path with Hash as the name.
+    let new_name = change_file_name(&name, &hash);
+    let new_path = config.storage_path.join(new_name).to_string_lossy().into_owned();
+    let (filepath, archive_filepath) = match (config.archive, &archive) {
+        (_, None) => (Some(new_path), None),
+        (ArchiveMode::Replace, Some(_)) => (None, Some(format!("{}.pdf", config.storage_path.join(&hash).display()))),
+        (_, Some(_)) => (Some(new_path), Some(format!("{}.archive.pdf", config.storage_path.join(&hash).display()))),
     };
 
     // Define PSQL Structs
     let upload_date = Utc::now().date_naive();
-    let document = settings::Document {
+    let mut document = settings::Document {
         _id: 0,
-        upload_date: upload_date,
-        filepath: new_path.clone(),
-        title: title
+        upload_date,
+        document_date,
+        filepath,
+        archive_filepath,
+        title: extraction.title,
+        content_hash: hash,
+        correspondent: extraction.correspondent,
+        document_type: extraction.document_type,
+        embedding_model: None
     };
 
     let document_content = settings::DocumentContent {
         _id: 0,
-        content: content,
-        summary: summary,
-        buzzwords: buzzwords
+        content,
+        summary: extraction.summary,
+        buzzwords: extraction.buzzwords,
+        fts_config: config.fts_config().to_string()
     };
 
-    // Clean up
-    let del = clean_up();
-    match del {
-        Ok(()) => (),
-        Err(e) => eprintln!("Cant clean up the consume dir: {}", e),
+    let pages: Vec<settings::DocumentPage> = page_texts.into_iter().enumerate().map(|(i, page)| settings::DocumentPage {
+        _id: 0,
+        page: i as i32 + 1,
+        content: page.text,
+        method: page.method.as_str().to_string()
+    }).collect();
+
+    let chunks = embeddings::for_consume(config, &pages).await;
+    if chunks.is_some() {
+        document.embedding_model = config.embedding_model.clone();
     }
-    
-    (document, document_content)
+
+    Ok(Entry { document, content: document_content, pages, amounts: extraction.amounts, archive, tags, chunks: chunks.unwrap_or_default() })
+}
+
+// Undo the storing of a Doc: move the original back into the Consume folder and remove the searchable PDF.
+fn unstore(old_path: &Path, filepath: Option<&Path>, archive_filepath: Option<&Path>) {
+    if let Some(new_path) = filepath {
+        if let Err(e) = fs::rename(new_path, old_path) {
+            eprintln!("Cant move {:?} back to the consume dir: {}", new_path, e);
+        }
+    }
+    if let Some(archive_path) = archive_filepath {
+        if let Err(e) = fs::remove_file(archive_path) {
+            eprintln!("Cant remove {:?}: {}", archive_path, e);
+        }
+    }
+}
+
+// Move the file <name> of the consume dir into its subfolder <dir>, created if missing.
+// A number is added to the name if the folder already holds a file with that name.
+fn set_aside(config: &Config, name: &str, dir: &str) -> Result<PathBuf, DmsError> {
+    let dir = config.consume_path.join(dir);
+    fs::create_dir_all(&dir).map_err(DmsError::io(format!("Cant create {}", dir.display())))?;
+    let path = Path::new(name);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or(name);
+    let extension = path.extension().and_then(|e| e.to_str()).map(|e| format!(".{}", e)).unwrap_or_default();
+    let mut target = dir.join(name);
+    let mut n = 1;
+    while target.exists() {
+        target = dir.join(format!("{}-{}{}", stem, n, extension));
+        n += 1;
+    }
+    let from = config.consume_path.join(name);
+    fs::rename(&from, &target).map_err(|source| DmsError::Store { from, to: target.clone(), source })?;
+    Ok(target)
 }
 
-// OCR and Ollama leave Files in the Consume dir.
-// clean_up deletes them and any other accidentally generated ".jpg" files.
-fn clean_up() -> io::Result<()> {
-    let _ = fs::remove_file(settings::settings::CONSUME_PATH.to_owned()+"output.txt");
-
-    let entries = fs::read_dir(settings::settings::CONSUME_PATH)?;
-    for entry in entries {
-        let entry = entry?;
-        let file_path = entry.path();
-
-        if let Some(extension) = file_path.extension() {
-            if extension == "jpg" {
-                // Delete the file
-                fs::remove_file(&file_path)?;
-                println!("Deleted file: {:?}", file_path);
+// SHA-256 of the file content as hex string.
+fn hash_file(path: &Path) -> Result<String, DmsError> {
+    let bytes = fs::read(path).map_err(DmsError::io(format!("Cant read {}", path.display())))?;
+    Ok(format!("{:x}", Sha256::digest(bytes)))
+}
+
+// Consume a single File of the Consume dir.
+// 0. Set the file aside if the same file is already stored (or drop it after linking it as a new Document if configured)
+// 1. create the Entry for the file and report a near-duplicate stored Doc
+//    and propose the correspondent: a known one named in the content, otherwise the one the LLM finds
+// 2. Move the File from the Consume folder into the long-time storage folder
+//    and copy the searchable PDF there (the working dir may be on another filesystem)
+// 3. Uplaod the Entry to the Postgres DB
+// 4. Remove the original if it is replaced by the searchable PDF
+// If any step fails the File is moved back. If the Doc itself made it fail (OCR, LLM, ...) it is set aside
+// into the failed folder, otherwise (e.g. Postgres is down) it stays in the Consume folder for the next run.
+async fn consume_file(config: &Config, db: &Database, name: String) -> Result<(), DmsError> {
+    println!("Consuming: {}", &name);
+    let result = store_file(config, db, &name).await;
+    match &result {
+        Err(e) if config.consume_path.join(&name).exists() => if e.is_caused_by_document() {
+            match set_aside(config, &name, FAILED_DIR) {
+                Ok(path) => eprintln!("Moved {} to {}, move it back into the consume folder to retry.", name, path.display()),
+                Err(e) => eprintln!("{}", e),
             }
+        } else {
+            eprintln!("{} stays in the consume folder and is consumed again on the next run.", name);
+        },
+        _ => {}
+    }
+    result
+}
+
+// Steps 0. to 4. of consume_file.
+async fn store_file(config: &Config, db: &Database, name: &str) -> Result<(), DmsError> {
+    let old_path = config.consume_path.join(name);
+    let hash = hash_file(&old_path)?;
+    if let Some(id) = psql::find_by_hash(db, &hash).await? {
+        match config.duplicates {
+            DuplicateMode::Skip => {
+                let path = set_aside(config, name, DUPLICATES_DIR)?;
+                println!("{} is a duplicate of document {}, moved to {}.", name, id, path.display());
+            }
+            DuplicateMode::Link => {
+                let new_id = psql::link_duplicate(db, id, Utc::now().date_naive()).await?;
+                fs::remove_file(&old_path).map_err(DmsError::io(format!("Cant remove the duplicate {}", old_path.display())))?;
+                println!("{} is a duplicate of document {}, linked as document {}.", name, id, new_id);
+            }
+        }
+        return Ok(());
+    }
+
+    // Intermediate files go into a fresh temp dir per Doc, which is removed when dropped.
+    let workdir = tempfile::Builder::new()
+        .prefix("dmslite-")
+        .tempdir()
+        .map_err(DmsError::io("Cant create the OCR working dir"))?;
+    let mut entry = create_entry(config, name.to_string(), hash, workdir.path()).await?;
+    if let Some((id, similarity)) = psql::find_near_duplicate(db, &entry.content.content, NEAR_DUPLICATE_SIMILARITY).await? {
+        println!("{} is a near-duplicate of document {} (similarity {:.2}).", name, id, similarity);
+    }
+    entry.document.correspondent = match correspondents::find_in_content(db, &entry.content.content).await? {
+        Some(correspondent) => Some(correspondent),
+        // The JSON extraction already named the sender
+        None if config.llm.extraction == ExtractionMode::Json => entry.document.correspondent.take(),
+        None => llm::propose_correspondent(config, entry.content.content.clone()).await,
+    };
+    if let Some(correspondent) = &entry.document.correspondent {
+        println!("Proposed correspondent: {}", correspondent);
+    }
+
+    let new_path = entry.document.filepath.as_ref().map(PathBuf::from);
+    let archive_path = entry.document.archive_filepath.as_ref().map(PathBuf::from);
+    if let Some(new_path) = &new_path {
+        fs::rename(&old_path, new_path)
+            .map_err(|source| DmsError::Store { from: old_path.clone(), to: new_path.clone(), source })?;
+    }
+    if let (Some(archive), Some(archive_path)) = (&entry.archive, &archive_path) {
+        if let Err(source) = fs::copy(archive, archive_path) {
+            unstore(&old_path, new_path.as_deref(), None);
+            return Err(DmsError::Store { from: archive.clone(), to: archive_path.clone(), source });
         }
     }
+
+    if let Err(e) = psql::add_to_psql(db, entry.document, entry.content, entry.pages, &entry.amounts, &entry.chunks, &entry.tags).await {
+        unstore(&old_path, new_path.as_deref(), archive_path.as_deref());
+        return Err(e.into());
+    }
+    if new_path.is_none() {
+        fs::remove_file(&old_path).map_err(DmsError::io(format!("Cant remove the replaced original {}", old_path.display())))?;
+    }
+    println!("Database succesfully updated.");
     Ok(())
 }
 
 // Consume all Files in the Consume dir.
 // 1. Find all Files in the Dir
-// 2. if File is PDF, create the Entry for each file
-// 3. Uplaod the File to the Postgres DB
-async fn consume() {
-    let paths = fs::read_dir(settings::settings::CONSUME_PATH).unwrap();
+// 2. if File is a PDF or an image (JPG, PNG, TIFF), consume it
+// A failed File is reported and set aside into the failed folder if it failed by itself. Fails if any of the Files could not be added.
+async fn consume(config: &Config, db: &Database) -> Result<(), DmsError> {
+    let paths = fs::read_dir(&config.consume_path)
+        .map_err(DmsError::io("Cant read the consume dir"))?;
+    // The failed folder and other files are left alone
     let names = paths.filter_map(|entry| {
         entry.ok().and_then(|e|
             e.path().file_name()
-            .and_then(|n| n.to_str().map(|s| String::from(s)))
+            .and_then(|n| n.to_str().map(String::from))
         )
-        }).collect::<Vec<String>>();
+        }).filter(|name| ocr::DocumentKind::from_path(Path::new(name)).is_some())
+        .collect::<Vec<String>>();
 
+    let mut failed = 0;
     for name in names.clone() {
-        if name.ends_with(".pdf") {
-            println!("Consuming: {}", &name);
-            let (document, document_content) = create_entry(name).await;
-            match psql::add_to_psql(document, document_content).await {
-                Ok(_) => 
-                    println!("Database succesfully updated."),
-                Err(e) => 
-                    eprintln!("Error updateing Database: {}",e)
-            }
+        if let Err(e) = consume_file(config, db, name.clone()).await {
+            eprintln!("Failed to consume {}: {}", name, e);
+            failed += 1;
         }
     }
 
-    if names.len() == 0 {
+    if names.is_empty() {
         println!("Nothing to consume!");
     }
+    if failed > 0 {
+        return Err(DmsError::ConsumeFailed(failed));
+    }
+    Ok(())
 }
 
+// Parse a document id typed into the shell.
+fn parse_id(id_s: &str) -> Result<i32, DmsError> {
+    match id_s.parse() {
+        Ok(id) if id > 0 => Ok(id),
+        _ => Err(DmsError::InvalidId(id_s.to_string())),
+    }
+}
+
+// Run a single subcommand and map its outcome to the process exit code.
+// The database connection pool is created once here and shared by all commands.
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let config = match Config::load(&cli.config) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let db = match Database::init(&config.psql) {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let command = cli.command.unwrap_or(Commands::Shell);
+    if !matches!(command, Commands::Db { .. }) {
+        if let Err(e) = migrations::check(&db).await {
+            eprintln!("Database error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let result = match command {
+        Commands::Consume => consume(&config, &db).await,
+        Commands::Watch => watch::watch(&config, &db).await,
+        Commands::Embed => embeddings::embed_missing(&config, &db).await,
+        Commands::Search { results, query } => render_search(&config, &db, query.join(" "), results.options(Sort::Rank, config.fts_config())).await,
+        Commands::Ask { question } => ask::ask(&config, &db, &question.join(" ")).await,
+        Commands::Open { id, original } => open_file(&db, id, original).await,
+        Commands::Delete { id } => delete(&db, id).await,
+        Commands::List { results, filters } => list_all(&db, filters.join(" "), results.options(Sort::Date, config.fts_config())).await,
+        Commands::Date { id, date } => set_date(&db, id, date.as_deref()).await,
+        Commands::Shell => shell(&config, &db).await,
+        Commands::Tag { command } => match command {
+            TagCommands::Add { id, tags } => tags::add(&db, id, &tags).await,
+            TagCommands::Remove { id, tags } => tags::remove(&db, id, &tags).await,
+            TagCommands::Rename { old, new } => tags::rename(&db, &old, &new).await,
+            TagCommands::Merge { from, into } => tags::merge(&db, &from, &into).await,
+            TagCommands::List => tags::list(&db).await,
+        },
+        Commands::Correspondent { command } => match command {
+            CorrespondentCommands::Set { id, name } => correspondents::set(&db, id, &name.join(" ")).await,
+            CorrespondentCommands::Confirm { id } => correspondents::confirm(&db, id).await,
+            CorrespondentCommands::Unset { id } => correspondents::unset(&db, id).await,
+            CorrespondentCommands::List => correspondents::list(&db).await,
+        },
+        Commands::Db { command: DbCommands::Init } => migrations::init(&db).await.map_err(DmsError::from),
+        Commands::Db { command: DbCommands::Migrate } => migrations::migrate(&db).await.map_err(DmsError::from),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
 
 // Infinite loop to take in commands.
 // Call functions to execute the commands.
 // check DB row count and show after command returns
-#[tokio::main]
-async fn main() {
+async fn shell(config: &Config, db: &Database) -> Result<(), DmsError> {
     loop {
-        println!("Please enter a command (_c_onsume || _s_earch <term> || _o_pen <id> || _d_elete <id>  || _l_ist all || _q_uit):");
+        println!("Please enter a command (_c_onsume || _s_earch <term> || _a_sk <question> || _o_pen <id> || _d_elete <id>  || _l_ist all || _q_uit):");
         let mut input = String::new();
-        io::stdin().read_line(&mut input).expect("Failed to read line");
-
-        let mut words = input.trim().split_whitespace();
-        let cmd = words.next().unwrap_or("");
-        let parameter = words.last().unwrap_or("");
-
+        if io::stdin().read_line(&mut input).map_err(DmsError::io("Failed to read line"))? == 0 {
+            // EOF
+            return Ok(());
+        }
 
-        match cmd {
-            "c" => consume().await,
-            "s" => render_search(parameter.to_string()).await,
-            "d" => delete(parameter.to_string()).await,
-            "o" => open_file(parameter.to_string()).await,
-            "l" => list_all().await,
+        // The parameter is the whole remainder of the line, e.g. a multi-word search query.
+        let input = input.trim();
+        let (cmd, parameter) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+        let parameter = parameter.trim();
+
+
+        let result = match cmd {
+            "c" => consume(config, db).await,
+            "s" => render_search(config, db, parameter.to_string(), SearchOptions::new(Sort::Rank, config.fts_config())).await,
+            "a" => ask::ask(config, db, parameter).await,
+            "d" => match parse_id(parameter) {
+                Ok(id) => delete(db, id).await,
+                Err(e) => Err(e),
+            },
+            "o" => match parse_id(parameter) {
+                Ok(id) => open_file(db, id, false).await,
+                Err(e) => Err(e),
+            },
+            "l" => list_all(db, parameter.to_string(), SearchOptions::new(Sort::Date, config.fts_config())).await,
             "q" => {
                 break;
             }
-            _ => println!("Invalid command!"),
-        }
-
-        let db: Database = psql::Database::init().await.unwrap();
-        tokio::spawn(async move {
-            if let Err(e) = db.connection.expect("Coudlnt find Connection to Psql").await {
-                eprintln!("Psql connection error: {}", e);
-            }
-        });
-        let rows: i64 = match db.client.expect("Databse init failed!").query("SELECT COUNT(*) FROM main_table;", &[]).await {
-            Ok(row) => row[0].get(0),
-            Err(e) => {
-                eprintln!("Postgres row count failed with: {}", e);
-                return;
-            }
+            _ => Err(DmsError::InvalidCommand(cmd.to_string())),
         };
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+        }
 
-        println!("{rows} Documents stored!");
+        // A failed row count is not fatal, the connection is retried with the next command.
+        match db.count().await {
+            Ok(rows) => println!("{rows} Documents stored!"),
+            Err(e) => eprintln!("Postgres row count failed with: {}", e),
+        }
         
         
     }
+    Ok(())
 }
 
-// List all Documents in the Database
-async fn list_all() {
-    let db = psql::Database::init().await.unwrap();
-    tokio::spawn(async move {
-        if let Err(e) = db.connection.expect("Coudlnt find Connection to Psql").await {
-            eprintln!("Psql connection error: {}", e);
-        }
-    });
-    let client = db.client.expect("Psql Cient not found");
-    // Prepare and execute the search query
-    let all = match client.query("SELECT id, title, upload_date FROM main_table;",&[]).await {
-        Ok(row) => row,
-        Err(e) => {
-            eprintln!("Postgres list all error: {}", e);
-            return;
+// Tell how many of the results are shown, if not all of them fit on the page.
+fn print_page_info(shown: usize, total: i64, options: &SearchOptions) {
+    let first = options.offset as i64 + 1;
+    let last = options.offset as i64 + shown as i64;
+    if shown > 0 && (first > 1 || last < total) {
+        println!("Showing {}-{} of {} documents.", first, last, total);
+        if last < total {
+            println!("Use --offset {} for the next page or --limit 0 to show all.", last);
         }
+    }
+}
+
+// Shorten the text to at most width chars for a table cell.
+fn fit(text: &str, width: usize) -> String {
+    text.chars().take(width).collect()
+}
+
+// Snippet of a search result fitted to the width and padded to it. Whitespace is collapsed to single spaces,
+// the matched words (enclosed in \x02 and \x03) are bold yellow on a terminal and enclosed in * otherwise.
+fn highlight(snippet: &str, width: usize, terminal: bool) -> String {
+    let (start, stop) = match terminal {
+        true => ("\x1b[1;33m", "\x1b[0m"),
+        false => ("*", "*"),
     };
+    let mut line = String::new();
+    let mut shown = 0;
+    let mut open = false;
+    for c in snippet.split_whitespace().collect::<Vec<_>>().join(" ").chars() {
+        let (text, visible) = match c {
+            '\x02' => (start, !terminal),
+            '\x03' => (stop, !terminal),
+            _ => (&*c.to_string(), true),
+        };
+        if visible && shown == width {
+            break;
+        }
+        match c {
+            '\x02' => open = true,
+            '\x03' => open = false,
+            _ => {}
+        }
+        line.push_str(text);
+        shown += visible as usize;
+    }
+    if open && terminal {
+        line.push_str(stop);
+    }
+    line + &" ".repeat(width - shown)
+}
+
+// List all Documents in the Database, or only those matching the filters.
+async fn list_all(db: &Database, filters: String, options: SearchOptions) -> Result<(), DmsError> {
+    let (all, total) = psql::search(db, &query::parse(&filters)?, &options).await?;
+    let shown = all.len();
 
-    if all.len() != 0 {
-        println!("+========+==============================================+==============+");
-        println!("|   ID   |    TITLE                                     |     DATE     |");
-        println!("+========+==============================================+==============+");
+    if !all.is_empty() {
+        println!("+========+==============================================+==============+========================+==============================+");
+        println!("|   ID   |    TITLE                                     |     DATE     |    FROM                |    TAGS                      |");
+        println!("+========+==============================================+==============+========================+==============================+");
     } else {
         println!("No Results");
     }
 
-    for row in all {
-        let mut title:String = row.get(1);
-        if title.len() > 46 {
-            title = title.replace("\n", "");
-            title.truncate(46);
-        }
-        
-        let id: i32 = row.get(0);
-        let date: NaiveDate = row.get(2);
-        println!("{}",format!("|{: ^8}|{: ^46}|{: ^14}|", id, title, date.to_string()));
-        println!("+--------+----------------------------------------------+--------------+");
+    for md in all {
+        let title = fit(&md.title.replace('\n', ""), 46);
+        let correspondent = md.correspondent.unwrap_or_default();
+        println!("|{: ^8}|{: ^46}|{: ^14}|{: ^24}|{: ^30}|", md._id, title, md.date.to_string(), fit(&correspondent, 24), fit(&md.tags.join(", "), 30));
+        println!("+--------+----------------------------------------------+--------------+------------------------+------------------------------+");
     }
+    print_page_info(shown, total, &options);
+    Ok(())
 }
 
-// Delete a Docuemnt by its Id
-// Delete the Document from the long-time storage folder.
-async fn delete(id_s: String) {
-    let id: i32 = id_s.parse().unwrap_or(-1);
-    if id > 0 {
-        let db = psql::Database::init().await.unwrap();
-        tokio::spawn(async move {
-            if let Err(e) = db.connection.expect("Coudlnt find Connection to Psql").await {
-                eprintln!("psql connection error: {}", e);
-            }
-        });
-        let mut client = db.client.expect("Psql Cient not found");
-
-        let filepath_rows = match client.query("SELECT filepath FROM main_table WHERE id = $1;", &[&id]).await {
-            Ok(row) => row,
-            Err(e) => {
-                eprintln!("Postgres SELECT Error: Cant get filepath: {}", e);
-                return;
-            }
-        };
+// Correct the document date of the Document with the <id>, or remove it without a date.
+async fn set_date(db: &Database, id: i32, date: Option<&str>) -> Result<(), DmsError> {
+    let date = match date {
+        Some(input) => Some(dates::parse(input).ok_or_else(|| DmsError::InvalidDate(input.to_string()))?),
+        None => None,
+    };
+    if !psql::set_document_date(db, id, date).await? {
+        return Err(DmsError::NotFound(id));
+    }
+    match date {
+        Some(date) => println!("Date of document {} is {}", id, date),
+        None => println!("Removed the date of document {}", id),
+    }
+    Ok(())
+}
 
-        let mut filepath: String = "".to_string();
-        // We expect only one row
-        if let Some(row) = filepath_rows.get(0) {
-            if let Some(filepath_value) = row.try_get::<_, String>(0).ok() {
-                filepath = filepath_value;
-            } else {
-                eprintln!("Error: Couldn't extract filepath from row.");
-            }
-        } else {
-            eprintln!("Error: No rows returned from the query.");
-        }
+// Get the paths of the stored original and of the searchable PDF of the Document with the <id>.
+// Each of them may be missing, but never both.
+async fn get_filepaths(client: &tokio_postgres::Client, id: i32) -> Result<(Option<String>, Option<String>), DmsError> {
+    let filepath_rows = client.query("SELECT filepath, archive_filepath FROM dmslite.main_table WHERE id = $1;", &[&id]).await?;
 
-        let transaction = match client.transaction().await {
-            Ok(value) => value,
-            Err(e) => {
-                eprintln!("Transaction error: {}", e);
-                return;
-            }
-        };
+    // We expect only one row
+    match filepath_rows.first() {
+        Some(row) => Ok((row.try_get(0)?, row.try_get(1)?)),
+        None => Err(DmsError::NotFound(id)),
+    }
+}
 
-        if let Err(e) = transaction.execute(
-            "DELETE FROM main_table
-            WHERE id = $1;",
-            &[&id],
-        ).await {
-            eprintln!("Postgres delete error: {}", e);
-            return;
-        };
+// Delete a Docuemnt by its Id
+// Delete the Document from the long-time storage folder,
+// unless a linked duplicate still refers to the stored files.
+// Its correspondent is removed too if no other Document has it.
+async fn delete(db: &Database, id: i32) -> Result<(), DmsError> {
+    let mut client = db.client().await?;
+
+    let (filepath, archive_filepath) = get_filepaths(&client, id).await?;
+
+    let transaction = client.transaction().await?;
+
+    transaction.execute(
+        "DELETE FROM dmslite.main_table
+        WHERE id = $1;",
+        &[&id],
+    ).await?;
+    correspondents::remove_unused(&transaction).await?;
+
+    let mut unused = Vec::new();
+    let mut shared = Vec::new();
+    for filepath in filepath.into_iter().chain(archive_filepath) {
+        let sharing = transaction.query_opt(
+            "SELECT id FROM dmslite.main_table WHERE filepath = $1 OR archive_filepath = $1 ORDER BY id LIMIT 1;",
+            &[&filepath],
+        ).await?;
+        match sharing {
+            Some(row) => shared.push((filepath, row.get::<_, i32>(0))),
+            None => unused.push(filepath),
+        }
+    }
 
-        // Commit the transaction
-        if let Err(e) = transaction.commit().await {
-            eprintln!("Posgres transaction commit error: {}", e);
-            return;
-        };
-        
-        // Attempt to remove the file
+    // Commit the transaction
+    transaction.commit().await?;
+    
+    // Attempt to remove the files
+    for filepath in &unused {
         println!("{}", filepath);
-        match fs::remove_file(filepath) {
-            Ok(()) => println!("File deleted successfully"),
-            Err(err) => eprintln!("Error deleting file: {}", err),
-        }
-        
-    } 
+        fs::remove_file(filepath).map_err(DmsError::io(format!("Error deleting file {}", filepath)))?;
+    }
+    if !unused.is_empty() {
+        println!("File deleted successfully");
+    }
+    for (filepath, sharing_id) in &shared {
+        println!("Kept the stored file {} because document {} shares it", filepath, sharing_id);
+    }
+    Ok(())
 }
 
 // Call the search and list the results formatted in the terminal.
-async fn render_search(parameter: String) {
+async fn render_search(config: &Config, db: &Database, parameter: String, mut options: SearchOptions) -> Result<(), DmsError> {
 
-    let mut results = Vec::new();
-    match psql::search(parameter.trim().to_string()).await {
-        Ok(r) => results = r,
-        Err(e) => eprintln!("Error: {}", e),
+    let query = query::parse(&parameter)?;
+    if query.is_empty() {
+        return Err(DmsError::EmptyQuery);
     }
+    // A query of only filters has no text to embed, its documents are listed without the semantic distance.
+    let text = query.text();
+    if options.mode.is_semantic() && !text.trim().is_empty() {
+        let model = config.embedding_model.as_deref().ok_or(DmsError::NoEmbeddingModel)?;
+        options.embedding = Some(llm::embedding(config, text, model).await?);
+        options.hybrid_max_distance = config.hybrid_max_distance;
+    }
+    let (results, total) = psql::search(db, &query, &options).await?;
+    let shown = results.len();
 
-    if results.len() != 0 {
-        println!("+========+==============================================+============+==============+");
-        println!("|   ID   |    TITLE                                     |    RANK    |     DATE     |");
-        println!("+========+==============================================+============+==============+");
+    if !results.is_empty() {
+        println!("+========+==============================================+============+========+==============+========================+==============================+");
+        println!("|   ID   |    TITLE                                     |    RANK    |  PAGE  |     DATE     |    FROM                |    TAGS                      |");
+        println!("+========+==============================================+============+========+==============+========================+==============================+");
     } else {
         println!("No Results");
     }
 
     for md in results {
-        let mut title:String = md.title;
-        if title.len() > 46 {
-            title = title.replace("\n", "");
-            title.truncate(46);
+        let title = fit(&md.title.replace('\n', ""), 46);
+        let page = md.page.map(|p| p.to_string()).unwrap_or("-".to_string());
+        let correspondent = md.correspondent.unwrap_or_default();
+        println!("|{: ^8}|{: ^46}|{: ^12}|{: ^8}|{: ^14}|{: ^24}|{: ^30}|", md._id, title, md.rank, page, md.date.to_string(), fit(&correspondent, 24), fit(&md.tags.join(", "), 30));
+        if let Some(snippet) = md.snippet {
+            let fields = match md.fields.is_empty() {
+                true => String::new(),
+                false => fit(&format!("{}: ", md.fields.join(", ")), 40),
+            };
+            println!("| {}{} |", fields, highlight(&snippet, 146 - fields.chars().count(), io::stdout().is_terminal()));
         }
-    
-        println!("{}",format!("|{: ^8}|{: ^46}|{: ^12}|{: ^14}|", md._id, title, md.rank, md.upload_date.to_string()));
-        println!("+--------+----------------------------------------------+------------+--------------+");
+        println!("+--------+----------------------------------------------+------------+--------+--------------+------------------------+------------------------------+");
 
     }
+    print_page_info(shown, total, &options);
+    Ok(())
 }
 
 // Open the file with teh <id> with its standart programm using xdg-open. 
-async fn open_file(id_s :String) {
-    let id: i32 = id_s.parse().unwrap_or(-1);
-    if id > 0 {
-        let db = psql::Database::init().await.unwrap();
-        tokio::spawn(async move {
-            if let Err(e) = db.connection.expect("Coudlnt find Connection to Psql").await {
-                eprintln!("psql connection error: {}", e);
-            }
-        });
-        let client = db.client.expect("Psql Cient not found");
-
-        let filepath_rows = match client.query("SELECT filepath FROM main_table WHERE id = $1;", &[&id]).await {
-            Ok(row) => row,
-            Err(e) => {
-                eprintln!("Postgres SELECT Error: Cant get filepath: {}", e);
-                return;
-            }
-        };
+// The searchable PDF is preferred unless the original is requested.
+async fn open_file(db: &Database, id: i32, original: bool) -> Result<(), DmsError> {
+    let client = db.client().await?;
+
+    let filepath = match get_filepaths(&client, id).await? {
+        (Some(filepath), _) if original => filepath,
+        (None, _) if original => return Err(DmsError::NoOriginal(id)),
+        (_, Some(archive_filepath)) => archive_filepath,
+        (Some(filepath), None) => filepath,
+        (None, None) => return Err(DmsError::NotFound(id)),
+    };
 
-        let mut filepath: String = "".to_string();
-        // We expect only one row
-        if let Some(row) = filepath_rows.get(0) {
-            if let Some(filepath_value) = row.try_get::<_, String>(0).ok() {
-                filepath = filepath_value;
-            } else {
-                eprintln!("Error: Couldn't extract filepath from row.");
-            }
-        } else {
-            eprintln!("Error: No rows returned from the query.");
-        }
+    let output = Command::new("xdg-open")
+    .arg(filepath)
+    .output()
+    .map_err(|source| DmsError::Spawn { program: "xdg-open", source })?;
 
+    // Check if there's any error while executing the command
+    if !output.status.success() {
+        return Err(DmsError::Open(String::from_utf8_lossy(&output.stderr).into_owned()));
+    }
+    Ok(())
+}
 
-        let output = Command::new("xdg-open")
-        .arg(filepath)
-        .output()
-        .expect("failed to execute process");
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        // Check if there's any error while executing the command
-        if !output.status.success() {
-            eprintln!(
-                "Error executing xdg-open: {}",
-                String::from_utf8_lossy(&output.stderr)
-            );
-        }
+    #[test]
+    fn fit_to_width() {
+        assert_eq!(fit("Stromrechnung", 20), "Stromrechnung");
+        assert_eq!(fit("Stromrechnung", 5), "Strom");
+        assert_eq!(fit("Größenänderung", 4), "Größ");
+    }
+
+    #[test]
+    fn highlight_pads_to_width() {
+        assert_eq!(highlight("Die  Rechnung\n vom Mai", 24, false), "Die Rechnung vom Mai    ");
+        assert_eq!(highlight("Die Rechnung vom Mai", 8, false), "Die Rech");
+    }
+
+    #[test]
+    fn highlight_words_in_stars() {
+        assert_eq!(highlight("Die \x02Rechnung\x03 vom Mai", 24, false), "Die *Rechnung* vom Mai  ");
+        // The stars take up the width like any other char
+        assert_eq!(highlight("Die \x02Rechnung\x03 vom Mai", 8, false), "Die *Rec");
+    }
+
+    #[test]
+    fn highlight_on_a_terminal() {
+        assert_eq!(highlight("Die \x02Rechnung\x03 vom", 15, true), "Die \x1b[1;33mRechnung\x1b[0m vo");
+        // A highlight cut at the width is still closed
+        assert_eq!(highlight("Die \x02Rechnung\x03 vom", 8, true), "Die \x1b[1;33mRech\x1b[0m");
+        assert_eq!(highlight("\x02Mai\x03", 5, true), "\x1b[1;33mMai\x1b[0m  ");
     }
 }
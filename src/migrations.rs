@@ -0,0 +1,96 @@
+use crate::psql::{Database, DbError};
+
+// A numbered schema change, embedded into the binary.
+struct Migration {
+    version: i32,
+    name: &'static str,
+    sql: &'static str,
+}
+
+// All migrations in the order they are applied. Never change an already released migration,
+// add a new one with the next version number instead.
+const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "initial", sql: include_str!("../migrations/0001_initial.sql") },
+    Migration { version: 2, name: "document_pages", sql: include_str!("../migrations/0002_document_pages.sql") },
+    Migration { version: 3, name: "page_method", sql: include_str!("../migrations/0003_page_method.sql") },
+    Migration { version: 4, name: "archive", sql: include_str!("../migrations/0004_archive.sql") },
+    Migration { version: 5, name: "content_hash", sql: include_str!("../migrations/0005_content_hash.sql") },
+    Migration { version: 6, name: "tags", sql: include_str!("../migrations/0006_tags.sql") },
+    Migration { version: 7, name: "correspondents", sql: include_str!("../migrations/0007_correspondents.sql") },
+    Migration { version: 8, name: "document_date", sql: include_str!("../migrations/0008_document_date.sql") },
+    Migration { version: 9, name: "fulltext", sql: include_str!("../migrations/0009_fulltext.sql") },
+    Migration { version: 10, name: "embeddings", sql: include_str!("../migrations/0010_embeddings.sql") },
+    Migration { version: 11, name: "extraction", sql: include_str!("../migrations/0011_extraction.sql") },
+    Migration { version: 12, name: "embedding_model", sql: include_str!("../migrations/0012_embedding_model.sql") },
+];
+
+// Newest schema version this binary understands.
+pub fn latest_version() -> i32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+// Schema version of the database. 0 if no migration was applied yet.
+pub async fn current_version(db: &Database) -> Result<i32, DbError> {
+    let client = db.client().await?;
+    let row = client.query_one(
+        "SELECT to_regclass('dmslite.schema_migrations') IS NOT NULL",
+        &[],
+    ).await?;
+    if !row.get::<_, bool>(0) {
+        return Ok(0);
+    }
+    let row = client.query_one("SELECT COALESCE(MAX(version), 0) FROM dmslite.schema_migrations", &[]).await?;
+    Ok(row.get(0))
+}
+
+// Make sure the database schema matches this binary before running any other command.
+pub async fn check(db: &Database) -> Result<(), DbError> {
+    let current = current_version(db).await?;
+    let latest = latest_version();
+    if current > latest {
+        return Err(DbError::SchemaTooNew { current, latest });
+    }
+    if current < latest {
+        return Err(DbError::SchemaOutdated { current, latest });
+    }
+    Ok(())
+}
+
+// Create the dmslite schema in a fresh database and apply all migrations.
+pub async fn init(db: &Database) -> Result<(), DbError> {
+    db.client().await?.batch_execute("CREATE SCHEMA IF NOT EXISTS dmslite;").await?;
+    migrate(db).await
+}
+
+// Apply all migrations newer than the current schema version.
+// Every migration runs in its own transaction together with the record of its version.
+pub async fn migrate(db: &Database) -> Result<(), DbError> {
+    let current = current_version(db).await?;
+    let latest = latest_version();
+    if current > latest {
+        return Err(DbError::SchemaTooNew { current, latest });
+    }
+
+    let mut client = db.client().await?;
+    client.batch_execute(
+        "CREATE TABLE IF NOT EXISTS dmslite.schema_migrations (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        );",
+    ).await?;
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        let transaction = client.transaction().await?;
+        transaction.batch_execute(migration.sql).await?;
+        transaction.execute(
+            "INSERT INTO dmslite.schema_migrations (version, name) VALUES ($1, $2)",
+            &[&migration.version, &migration.name],
+        ).await?;
+        transaction.commit().await?;
+        println!("Applied migration {:04}_{}", migration.version, migration.name);
+    }
+
+    println!("Database schema is at version {}.", latest);
+    Ok(())
+}
@@ -1,51 +1,329 @@
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
-use crate::settings::settings;
+use crate::error::DmsError;
+use crate::settings::Config;
 
+// Pages with fewer non-whitespace chars in the PDF text layer are OCRed instead.
+const MIN_TEXT_LAYER_CHARS: usize = 50;
 
-// To use Tesseract for Ocr this function converts the PDF into a JPG.
-pub fn pdf2jpg(name: String) {
-    let pdf2jpg = Command::new("pdftoppm")
-        .arg("-jpeg")
-        .arg(name.clone())
-        .arg(name)
+// How the text of a page was obtained.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextMethod {
+    // Embedded text layer of a born-digital PDF
+    TextLayer,
+    Ocr,
+}
+
+impl TextMethod {
+    // Name stored in document_pages.method
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TextMethod::TextLayer => "text_layer",
+            TextMethod::Ocr => "ocr",
+        }
+    }
+}
+
+// Text of a single page and how it was obtained.
+pub struct PageText {
+    pub text: String,
+    pub method: TextMethod,
+}
+
+// Extract the embedded text layer of a PDF with pdftotext, one text per page.
+// None if pdftotext is not available or cant read the PDF, all pages are OCRed then.
+fn pdf2text(pdf: &Path) -> Option<Vec<String>> {
+    let output = match Command::new("pdftotext").arg(pdf).arg("-").output() {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("Cant execute pdftotext, using OCR for all pages: {}", e);
+            return None;
+        }
+    };
+    if !output.status.success() {
+        eprintln!("pdftotext failed, using OCR for all pages: {}", String::from_utf8_lossy(&output.stderr));
+        return None;
+    }
+    Some(split_pages(&String::from_utf8_lossy(&output.stdout)))
+}
+
+// pdftotext and Tesseract end every page with a form feed.
+fn split_pages(text: &str) -> Vec<String> {
+    let mut pages: Vec<String> = text.split('\x0c').map(String::from).collect();
+    if pages.len() > 1 && pages.last().is_some_and(|p| p.trim().is_empty()) {
+        pages.pop();
+    }
+    pages
+}
+
+// To use Tesseract for Ocr this function converts the PDF (or only the given page of it) into one JPG per page.
+// The images are written into workdir as "page-<page>.jpg".
+pub fn pdf2jpg(pdf: &Path, workdir: &Path, page: Option<u32>) -> Result<(), DmsError> {
+    let name = pdf.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let mut pdftoppm = Command::new("pdftoppm");
+    pdftoppm.arg("-jpeg");
+    if let Some(page) = page {
+        pdftoppm.arg("-f").arg(page.to_string()).arg("-l").arg(page.to_string());
+    }
+    let pdf2jpg = pdftoppm
+        .arg(pdf)
+        .arg("page")
         .stdout(std::process::Stdio::null())
-        .current_dir(settings::CONSUME_PATH)
+        .current_dir(workdir)
         .status()
-        .expect("failed to execute process");
+        .map_err(|source| DmsError::Spawn { program: "pdftoppm", source })?;
 
-    match pdf2jpg.code() {
-        Some(0) => println!("Success converting pdf to jpg"),
-        Some(1) => eprintln!("Error converting pdf to jpg:\nError opening PDF file."),
-        Some(2) => eprintln!("Error converting pdf to jpg:\nError opening an output file."),
-        Some(3) => eprintln!("Error converting pdf to jpg:\nError related to PDF permissions."),
-        Some(99) => eprintln!("Error converting pdf to jpg:\nOther error."),
-        Some(code) => eprintln!("Error converting pdf to jpg:\nOther error. code: {}",code),
-        None => eprintln!("Process terminated by signal")
-    }
+    let reason = match pdf2jpg.code() {
+        Some(0) => {
+            println!("Success converting pdf to jpg");
+            return Ok(());
+        }
+        Some(1) => "Error opening PDF file.".to_string(),
+        Some(2) => "Error opening an output file.".to_string(),
+        Some(3) => "Error related to PDF permissions.".to_string(),
+        Some(99) => "Other error.".to_string(),
+        Some(code) => format!("Other error. code: {}", code),
+        None => "Process terminated by signal".to_string(),
+    };
+    Err(DmsError::Pdf2Jpg { name, reason })
+}
 
+// pdftoppm names the images "page-<page>.jpg", where <page> is zero padded
+// depending on the page count (e.g. "-1.jpg" or "-01.jpg").
+// Find all of them in the workdir and return them with their page number, ordered by page number.
+fn page_images(workdir: &Path, name: &str) -> Result<Vec<(u32, String)>, DmsError> {
+    let mut pages: Vec<(u32, String)> = fs::read_dir(workdir)
+        .map_err(DmsError::io("Cant read the OCR working dir"))?
+        .filter_map(|entry| entry.ok().and_then(|e| e.file_name().into_string().ok()))
+        .filter_map(|file| {
+            let page = file.strip_prefix("page-")?.strip_suffix(".jpg")?.parse().ok()?;
+            Some((page, file))
+        })
+        .collect();
+    if pages.is_empty() {
+        return Err(DmsError::NoPages(name.to_string()));
+    }
+    pages.sort();
+    Ok(pages)
 }
 
-// Convert PDF to Image. Image is named "<pdf_name>-1.jpg"
-// Get text in the Image and save it in a "output.txt" file
-pub fn ocr(name: String) {
-    pdf2jpg(name.clone());
+// Kind of a consumable document, by its file extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DocumentKind {
+    Pdf,
+    // JPG, PNG or (multi-page) TIFF, read by Tesseract directly
+    Image,
+}
 
-    let ppm_out_name = name.split(".").next().unwrap_or_default();
+impl DocumentKind {
+    pub fn from_path(path: &Path) -> Option<DocumentKind> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "pdf" => Some(DocumentKind::Pdf),
+            "jpg" | "jpeg" | "png" | "tif" | "tiff" => Some(DocumentKind::Image),
+            _ => None,
+        }
+    }
+}
 
-    let tesseract = Command::new("tesseract")
-    .arg(ppm_out_name.to_owned()+".pdf-1.jpg")
-    .arg("output")
+// Run Tesseract once on an image or on a list of images (one path per line) of the document name
+// and return the text of each page.
+// Multi-page TIFFs therefore give one text per page.
+// With searchable set, Tesseract's pdf renderer writes the same pages into "ocr.pdf" inside workdir in this run,
+// so the pages are only OCRed once.
+fn tesseract(config: &Config, input: &Path, name: &str, workdir: &Path, searchable: bool) -> Result<Vec<String>, DmsError> {
+    let mut command = Command::new("tesseract");
+    command
+    .arg(input)
+    .arg("ocr")
     .arg("-l")
-    .arg(settings::TESSERACT_LANG)
-    .stdout(std::process::Stdio::null())
-    .current_dir(settings::CONSUME_PATH)
-    .status()
-    .expect("failed to execute process");
-
-    match tesseract.code() {
-        Some(0) => println!("Success using tessercat as ocr"),
-        Some(code) => eprintln!("Error using tesseract. code: {}",code),
-        None => eprintln!("Process terminated by signal")
-    }
-}
\ No newline at end of file
+    .arg(&config.tesseract_lang)
+    .arg("txt");
+    if searchable {
+        command.arg("pdf");
+    }
+    let tesseract = command
+    .current_dir(workdir)
+    .output()
+    .map_err(|source| DmsError::Spawn { program: "tesseract", source })?;
+
+    let reason = match tesseract.status.code() {
+        Some(0) => {
+            println!("Success using tessercat as ocr on {}", name);
+            let text = fs::read_to_string(workdir.join("ocr.txt")).map_err(DmsError::io("Cant read the text of tesseract"))?;
+            return Ok(split_pages(&text));
+        }
+        Some(code) => format!("code: {}\n{}", code, String::from_utf8_lossy(&tesseract.stderr)),
+        None => "Process terminated by signal".to_string(),
+    };
+    Err(DmsError::Ocr { image: name.to_string(), reason })
+}
+
+// Run one of the poppler tools that assemble the searchable PDF of a partly born-digital PDF.
+fn poppler(program: &'static str, args: &[&Path], name: &str) -> Result<(), DmsError> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|source| DmsError::Spawn { program, source })?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let reason = format!("{} failed: {}", program, String::from_utf8_lossy(&output.stderr));
+    Err(DmsError::Archive { name: name.to_string(), reason })
+}
+
+// Searchable PDF of a PDF with a text layer on some of its pages: these pages are taken from the original,
+// the OCRed pages from Tesseract's PDF. ocred tells for every page of the document whether it was OCRed.
+// Both PDFs are split into single pages with pdfseparate and joined in page order with pdfunite.
+fn merge_searchable(pdf: &Path, ocr_pdf: &Path, ocred: &[bool], workdir: &Path) -> Result<PathBuf, DmsError> {
+    let name = pdf.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let archive_dir = workdir.join("archive");
+    fs::create_dir(&archive_dir).map_err(DmsError::io("Cant create the archive working dir"))?;
+    poppler("pdfseparate", &[pdf, &archive_dir.join("original-%d.pdf")], &name)?;
+    poppler("pdfseparate", &[ocr_pdf, &archive_dir.join("ocr-%d.pdf")], &name)?;
+
+    let mut ocr_page = 0;
+    let mut parts: Vec<PathBuf> = ocred.iter().enumerate().map(|(i, &ocred)| {
+        if ocred {
+            ocr_page += 1;
+            archive_dir.join(format!("ocr-{}.pdf", ocr_page))
+        } else {
+            archive_dir.join(format!("original-{}.pdf", i + 1))
+        }
+    }).collect();
+    let archive = archive_dir.join("archive.pdf");
+    parts.push(archive.clone());
+    let args: Vec<&Path> = parts.iter().map(PathBuf::as_path).collect();
+    poppler("pdfunite", &args, &name)?;
+    Ok(archive)
+}
+
+// OCR the rendered pages of a PDF with a single Tesseract run, see tesseract.
+fn ocr_images(config: &Config, name: &str, images: &[(u32, String)], workdir: &Path, searchable: bool) -> Result<Vec<String>, DmsError> {
+    // Tesseract reads a list of images from a text file and merges them into one output.
+    let list = workdir.join("pages.txt");
+    let list_content: String = images.iter().map(|(_, image)| format!("{}\n", workdir.join(image).display())).collect();
+    fs::write(&list, list_content).map_err(DmsError::io("Cant write the page list"))?;
+    let texts = tesseract(config, &list, name, workdir, searchable)?;
+    if texts.len() != images.len() {
+        let reason = format!("{} pages were rendered, tesseract returned the text of {}", images.len(), texts.len());
+        return Err(DmsError::Ocr { image: name.to_string(), reason });
+    }
+    Ok(texts)
+}
+
+// Text of a document and its searchable PDF.
+pub struct Scan {
+    pub pages: Vec<PageText>,
+    // Searchable PDF inside workdir, to be stored at document.archive_filepath.
+    // None if it was not requested or no page had to be OCRed, born-digital PDFs are already searchable.
+    pub archive: Option<PathBuf>,
+}
+
+// Get the text of a document page by page in page order.
+// PDFs: the embedded text layer is used for every page that has enough text,
+// only the remaining pages are converted to images inside workdir, a directory private to this document, and OCRed.
+// Images are read by Tesseract directly.
+// All OCRed pages are read by a single Tesseract run, which also renders the searchable PDF if searchable is set.
+// Pages with a text layer are taken from the original into the searchable PDF.
+// Fails if any of the pages could not be read.
+pub fn ocr(config: &Config, path: &Path, workdir: &Path, searchable: bool) -> Result<Scan, DmsError> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    match DocumentKind::from_path(path) {
+        Some(DocumentKind::Pdf) => {
+            // Text of every page with a text layer, None for the pages to OCR. Empty without a text layer.
+            let mut text_layer: Vec<Option<String>> = pdf2text(path).unwrap_or_default()
+                .into_iter()
+                .enumerate()
+                .map(|(i, text)| {
+                    if text.chars().filter(|c| !c.is_whitespace()).count() >= MIN_TEXT_LAYER_CHARS {
+                        println!("Using the text layer of page {}", i + 1);
+                        Some(text)
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+
+            let images = if text_layer.is_empty() {
+                pdf2jpg(path, workdir, None)?;
+                let images = page_images(workdir, &name)?;
+                text_layer = vec![None; images.len()];
+                images
+            } else if text_layer.iter().any(Option::is_none) {
+                for (i, _) in text_layer.iter().enumerate().filter(|(_, text)| text.is_none()) {
+                    pdf2jpg(path, workdir, Some(i as u32 + 1))?;
+                }
+                page_images(workdir, &name)?
+                    .into_iter()
+                    .filter(|(page, _)| (*page as usize).checked_sub(1).and_then(|i| text_layer.get(i)).is_some_and(Option::is_none))
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            let ocr_texts = if images.is_empty() {
+                Vec::new()
+            } else {
+                ocr_images(config, &name, &images, workdir, searchable)?
+            };
+
+            let ocred: Vec<bool> = text_layer.iter().map(Option::is_none).collect();
+            let mut ocr_texts = ocr_texts.into_iter();
+            let pages = text_layer.into_iter().map(|text| match text {
+                Some(text) => PageText { text, method: TextMethod::TextLayer },
+                None => PageText { text: ocr_texts.next().unwrap_or_default(), method: TextMethod::Ocr },
+            }).collect();
+
+            let archive = if !searchable || !ocred.contains(&true) {
+                None
+            } else if ocred.contains(&false) {
+                Some(merge_searchable(path, &workdir.join("ocr.pdf"), &ocred, workdir)?)
+            } else {
+                Some(workdir.join("ocr.pdf"))
+            };
+            Ok(Scan { pages, archive })
+        }
+        Some(DocumentKind::Image) => {
+            let pages = tesseract(config, path, &name, workdir, searchable)?
+                .into_iter()
+                .map(|text| PageText { text, method: TextMethod::Ocr })
+                .collect();
+            Ok(Scan { pages, archive: searchable.then(|| workdir.join("ocr.pdf")) })
+        }
+        None => Err(DmsError::Unsupported(name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_at_form_feeds() {
+        assert_eq!(split_pages("first page\x0csecond page\x0c"), vec!["first page", "second page"]);
+    }
+
+    #[test]
+    fn empty_page_in_between_is_kept() {
+        assert_eq!(split_pages("first\x0c\x0cthird\x0c"), vec!["first", "", "third"]);
+    }
+
+    #[test]
+    fn without_trailing_form_feed() {
+        assert_eq!(split_pages("first\x0csecond"), vec!["first", "second"]);
+    }
+
+    #[test]
+    fn trailing_whitespace_after_last_form_feed() {
+        assert_eq!(split_pages("first\x0c\n "), vec!["first"]);
+    }
+
+    #[test]
+    fn single_page() {
+        assert_eq!(split_pages("only page"), vec!["only page"]);
+        assert_eq!(split_pages(""), vec![""]);
+        assert_eq!(split_pages("\x0c"), vec![""]);
+    }
+}
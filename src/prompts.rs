@@ -0,0 +1,73 @@
+// System prompts of the LLM tasks in one language.
+// The German set is the one of the Modelfiles shipped with DMSLite.
+pub struct Prompts {
+    pub extract: &'static str,
+    // Summary of one chunk of a long document
+    pub chunk: &'static str,
+    pub summary: &'static str,
+    pub buzzwords: &'static str,
+    pub title: &'static str,
+    pub correspondent: &'static str,
+    pub answer: &'static str,
+    // Heading of the question below the document excerpts
+    pub question: &'static str,
+}
+
+pub const GERMAN: Prompts = Prompts {
+    extract: "Lies das Dokument und antworte nur mit einem JSON-Objekt mit diesen Feldern: \
+        title: ein möglichst einzigartiger Titel mit Unterstrichen statt Leerzeichen und einer zeitlichen Komponente, wenn es eine gibt. \
+        summary: erkläre, was das Dokument ist und wofür es ist. \
+        keywords: 3 buzzwords. \
+        document_type: die Art des Dokuments, z.B. Rechnung, Vertrag, Bescheid oder Brief. \
+        date: das Datum des Dokuments als JJJJ-MM-TT, leer wenn keins erkennbar ist. \
+        correspondent: der Name der Firma, Behörde oder Person, die es geschickt hat, leer wenn kein Absender erkennbar ist. \
+        amounts: die Geldbeträge im Dokument, jeder mit value (Zahl) und currency (z.B. EUR).",
+    chunk: "Du bekommst einen Teil eines längeren Dokuments. Fasse diesen Teil zusammen. \
+        Behalte Absender, Datumsangaben, Geldbeträge, Fristen und Namen. Antworte nur mit der Zusammenfassung.",
+    summary: "Erkläre das Dokument. Erkläre wofür es ist und was es ist. Beschreibe das Dokument.",
+    buzzwords: "Fasse diesen Text in 3 buzzwords zusammen.",
+    title: "Du bekommst eine Zusammenfassung von einem Dokument. Denke dir einen Title für dieses Dokument aus. \
+        Er sollte eine Zeitliche komponente enthalten, wenn du sie in der Summary finden kannst und möglichst einzigartig sein. \
+        Kein Leerzeichen nur Unterstriche. Antworte nur mit diesem Title Ohne Formatierung! Keine weiteren erklärungn oder Texte.",
+    correspondent: "Wer ist der Absender dieses Dokuments? Nenne nur den Namen der Firma, Behörde oder Person, die es geschickt hat. \
+        Antworte nur mit diesem Namen ohne Formatierung! Wenn kein Absender erkennbar ist, antworte mit \"unbekannt\".",
+    answer: "Beantworte die Frage nur mit den Informationen aus den Auszügen der Dokumente. \
+        Jeder Auszug beginnt mit seiner Quelle in der Form [Dokument-ID:Seite], z.B. [12:2]. \
+        Nenne diese Quelle hinter jeder Aussage, die du aus einem Auszug übernimmst. \
+        Steht die Antwort nicht in den Auszügen, sage das. Antworte in der Sprache der Frage.",
+    question: "Frage",
+};
+
+pub const ENGLISH: Prompts = Prompts {
+    extract: "Read the document and answer only with a JSON object with these fields: \
+        title: a title as unique as possible with underscores instead of spaces and a time component if there is one. \
+        summary: explain what the document is and what it is for. \
+        keywords: 3 buzzwords. \
+        document_type: the kind of the document, e.g. invoice, contract, notice or letter. \
+        date: the date of the document as YYYY-MM-DD, empty if none can be recognized. \
+        correspondent: the name of the company, authority or person that sent it, empty if no sender can be recognized. \
+        amounts: the amounts of money in the document, each with value (number) and currency (e.g. EUR).",
+    chunk: "You get a part of a longer document. Summarize this part. \
+        Keep senders, dates, amounts of money, deadlines and names. Answer only with the summary.",
+    summary: "Explain the document. Explain what it is for and what it is. Describe the document.",
+    buzzwords: "Summarize this text in 3 buzzwords.",
+    title: "You get a summary of a document. Come up with a title for this document. \
+        It should contain a time component if you can find one in the summary and be as unique as possible. \
+        No spaces, only underscores. Answer only with this title without formatting! No further explanations or texts.",
+    correspondent: "Who is the sender of this document? Name only the company, authority or person that sent it. \
+        Answer only with this name without formatting! If no sender can be recognized, answer with \"unknown\".",
+    answer: "Answer the question only with the information from the document excerpts. \
+        Every excerpt starts with its source in the form [document-id:page], e.g. [12:2]. \
+        Cite this source after every statement you take from an excerpt. \
+        If the answer is not in the excerpts, say so. Answer in the language of the question.",
+    question: "Question",
+};
+
+// Prompt set of the Tesseract language (e.g. "deu" or "deu+eng", the first one counts).
+// Languages without an own set get the English prompts.
+pub fn for_language(lang: &str) -> &'static Prompts {
+    match lang.split('+').next().unwrap_or_default() {
+        "deu" => &GERMAN,
+        _ => &ENGLISH,
+    }
+}
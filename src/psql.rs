@@ -1,63 +1,139 @@
-use tokio_postgres::{config::Config, NoTls, Error, Client, Connection, Socket};
-use tokio_postgres::tls::NoTlsStream;
+use chrono::NaiveDate;
+use deadpool_postgres::{BuildError, Client, GenericClient, Manager, ManagerConfig, Pool, PoolError, RecyclingMethod};
+use tokio_postgres::{config::Config, NoTls};
+use tokio_postgres::types::ToSql;
 
-use crate::settings::settings;
+use crate::settings::PsqlConfig;
 use crate::settings::SearchResult;
 use crate::settings::Document;
 use crate::settings::DocumentContent;
+use crate::settings::DocumentPage;
+use crate::settings::DocumentChunk;
+use crate::settings::DocumentAmount;
+use crate::query::{DateField, Field, Filter, FilterKind, Occur, Order, Query, SearchMode, SearchOptions, Sort, Term};
+use crate::tags;
+use crate::correspondents;
 
+// Share of the full-text rank in the combined ranking, the rest is the trigram distance.
+const FULLTEXT_WEIGHT: f32 = 0.5;
+// Share of the semantic rank in the hybrid ranking, the rest is the trigram distance.
+const SEMANTIC_WEIGHT: f32 = 0.5;
+// Fields reported as the source of a hit, the snippet is taken from the first matching text field.
+const MATCH_FIELDS: [(&str, &str); 4] = [("content", "c.content"), ("summary", "c.summary"), ("buzzwords", "c.buzzwords"), ("title", "m.title")];
+// Highlighted words are enclosed in these control characters, see highlight() in main.
+const SNIPPET_OPTIONS: &str = "StartSel=\x02, StopSel=\x03, MaxWords=25, MinWords=10, MaxFragments=2, FragmentDelimiter=\" ... \"";
 
+
+// Errors of the database operations.
+#[derive(Debug, thiserror::Error)]
+pub enum DbError {
+    #[error("Cant create the Postgres connection pool: {0}")]
+    Build(#[from] BuildError),
+    #[error("Cant connect to Postgres: {0}")]
+    Pool(#[from] PoolError),
+    #[error("Postgres error: {0}")]
+    Postgres(#[from] tokio_postgres::Error),
+    #[error("Database schema version {current} is newer than this DMSLite understands (up to {latest}). Please update DMSLite.")]
+    SchemaTooNew { current: i32, latest: i32 },
+    #[error("Database schema version {current} is outdated (expected {latest}). Run `dmslite db migrate` (or `dmslite db init` for a new database).")]
+    SchemaOutdated { current: i32, latest: i32 },
+}
+
+// Pool of Postgres connections, created once at startup and shared by all database operations.
+// Connections are opened on first use. Broken connections are detected when taken from the pool
+// and replaced by a new connection, so a restarted Postgres server does not need a restart of DMSLite.
+#[derive(Clone)]
 pub struct Database {
-    pub client: Option<Client>,
-    pub connection:Option<Connection<Socket, NoTlsStream>>,
+    pool: Pool,
 }
 
 impl Database {
-    pub async fn init() -> Result<Database, tokio_postgres::Error> {
+    pub fn init(psql: &PsqlConfig) -> Result<Database, DbError> {
         let mut config = Config::new();
-        config.host(settings::PSQL_HOST);
-        config.user(settings::PSQL_USER);
-        config.password(settings::PSQL_PASSWD);
-        config.dbname(settings::PSQL_DBNAME);
+        config.host(&psql.host);
+        config.port(psql.port);
+        config.user(&psql.user);
+        config.password(&psql.password);
+        config.dbname(&psql.dbname);
+        // Tables are named with their schema, but pg_trgm lives in the first schema of the search path at the time
+        // the extension was created, which is dmslite if the user was named dmslite and public otherwise.
+        config.options("-c search_path=dmslite,public");
 
-        let (client, connection) = config.connect(NoTls).await?;
+        let manager = Manager::from_config(config, NoTls, ManagerConfig {
+            recycling_method: RecyclingMethod::Verified,
+        });
+        let pool = Pool::builder(manager).max_size(4).build()?;
 
-        Ok(Self {
-            client: Some(client),
-            connection: Some(connection),
-        })
+        Ok(Self { pool })
+    }
+
+    // Take a working connection from the pool.
+    pub async fn client(&self) -> Result<Client, DbError> {
+        Ok(self.pool.get().await?)
     }
 
+    // Number of stored Documents.
+    pub async fn count(&self) -> Result<i64, DbError> {
+        let row = self.client().await?.query_one("SELECT COUNT(*) FROM dmslite.main_table;", &[]).await?;
+        Ok(row.get(0))
+    }
 }
 
 
-// Add the content of the Document and Document_content Struct in the DB.
-pub async fn add_to_psql(document: Document, document_content: DocumentContent) -> Result<(), Error> {
-    let db: Database = Database::init().await.unwrap();
-    tokio::spawn(async move {
-        if let Err(e) = db.connection.expect("Coudlnt find Connection to Psql").await {
-            eprintln!("psql connection error: {}", e);
-        }
-    });
+// Add the content of the Document and Document_content Struct, the text of every page, the amounts,
+// the embedded chunks, the suggested tags and the proposed correspondent in the DB.
+pub async fn add_to_psql(db: &Database, document: Document, document_content: DocumentContent, pages: Vec<DocumentPage>, amounts: &[DocumentAmount], chunks: &[DocumentChunk], suggested_tags: &[String]) -> Result<(), DbError> {
     // Begin a transaction
-    let mut client = db.client.expect("Psql Cient not found");
+    let mut client = db.client().await?;
     let transaction = client.transaction().await?;
     // Insert data into main_table
-    transaction.execute(
-        "INSERT INTO dmslite.main_table (upload_date, filepath, title) VALUES ($1, $2, $3)",
-        &[&document.upload_date, &document.filepath, &document.title],
+    let row = transaction.query_one(
+        "INSERT INTO dmslite.main_table (upload_date, document_date, filepath, archive_filepath, title, content_hash, document_type, embedding_model) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        RETURNING id",
+        &[&document.upload_date, &document.document_date, &document.filepath, &document.archive_filepath, &document.title, &document.content_hash, &document.document_type, &document.embedding_model],
     ).await?;
+    let id: i32 = row.get(0);
+    if let Some(name) = &document.correspondent {
+        correspondents::propose(&transaction, id, name).await?;
+    }
 
     transaction.execute(
         "WITH inserted_id AS (
-            INSERT INTO dmslite.document_content (id, content, summary, buzzwords)
-            SELECT currval('dmslite.main_table_id_seq'), $1, $2, $3
+            INSERT INTO dmslite.document_content (id, content, summary, buzzwords, fts_config)
+            SELECT currval('dmslite.main_table_id_seq'), $1, $2, $3, $4::text::regconfig
             RETURNING id
         )
         SELECT id FROM inserted_id",
-        &[&document_content.content, &document_content.summary, &document_content.buzzwords],
+        &[&document_content.content, &document_content.summary, &document_content.buzzwords, &document_content.fts_config],
     ).await?;
 
+    for page in pages {
+        transaction.execute(
+            "INSERT INTO dmslite.document_pages (id, page, content, method)
+            VALUES (currval('dmslite.main_table_id_seq'), $1, $2, $3)",
+            &[&page.page, &page.content, &page.method],
+        ).await?;
+    }
+
+    for amount in amounts {
+        transaction.execute(
+            "INSERT INTO dmslite.document_amounts (id, position, value, currency) VALUES ($1, $2, $3, $4)",
+            &[&id, &amount.position, &amount.value, &amount.currency],
+        ).await?;
+    }
+
+    insert_chunks(&transaction, id, chunks).await?;
+
+    for tag in suggested_tags {
+        transaction.execute("INSERT INTO dmslite.tags (name) VALUES ($1) ON CONFLICT (name) DO NOTHING", &[tag]).await?;
+        transaction.execute(
+            "INSERT INTO dmslite.document_tags (id, tag_id, suggested)
+            SELECT currval('dmslite.main_table_id_seq'), id, true FROM dmslite.tags WHERE name = $1
+            ON CONFLICT (id, tag_id) DO NOTHING",
+            &[tag],
+        ).await?;
+    }
+
     // Commit the transaction
     transaction.commit().await?;
 
@@ -67,41 +143,620 @@ pub async fn add_to_psql(document: Document, document_content: DocumentContent)
 
 }
 
-// fuzzy search for a Phrase in the Columns content, summary and buzzwords,
+// Id of a stored Document whose file has the SHA-256 hash.
+pub async fn find_by_hash(db: &Database, hash: &str) -> Result<Option<i32>, DbError> {
+    let row = db.client().await?.query_opt(
+        "SELECT id FROM dmslite.main_table WHERE content_hash = $1 ORDER BY id LIMIT 1",
+        &[&hash],
+    ).await?;
+    Ok(row.map(|row| row.get(0)))
+}
+
+// Stored Document with the most similar content and its trigram similarity,
+// if it is at least min_similarity.
+// The % operator with the threshold set for this transaction only can use the trigram index on content.
+pub async fn find_near_duplicate(db: &Database, content: &str, min_similarity: f32) -> Result<Option<(i32, f32)>, DbError> {
+    let mut client = db.client().await?;
+    let transaction = client.transaction().await?;
+    transaction.execute(
+        "SELECT set_config('pg_trgm.similarity_threshold', $1, true)",
+        &[&min_similarity.to_string()],
+    ).await?;
+    let row = transaction.query_opt(
+        "SELECT id, similarity(content, $1) AS s FROM dmslite.document_content
+        WHERE content % $1
+        ORDER BY s DESC, id ASC LIMIT 1",
+        &[&content],
+    ).await?;
+    transaction.commit().await?;
+    Ok(row.map(|row| (row.get(0), row.get(1))))
+}
+
+// Add a new Document that shares the stored files, the content, the pages, the amounts and the tags of the Document <id>.
+// Returns the id of the new Document.
+pub async fn link_duplicate(db: &Database, id: i32, upload_date: NaiveDate) -> Result<i32, DbError> {
+    let mut client = db.client().await?;
+    let transaction = client.transaction().await?;
+    let row = transaction.query_one(
+        "INSERT INTO dmslite.main_table (upload_date, document_date, filepath, archive_filepath, title, content_hash, correspondent_id, correspondent_confirmed, document_type, embedding_model)
+        SELECT $2, document_date, filepath, archive_filepath, title, content_hash, correspondent_id, correspondent_confirmed, document_type, embedding_model FROM dmslite.main_table WHERE id = $1
+        RETURNING id",
+        &[&id, &upload_date],
+    ).await?;
+    let new_id: i32 = row.get(0);
+    transaction.execute(
+        "INSERT INTO dmslite.document_content (id, content, summary, buzzwords, fts_config)
+        SELECT $2, content, summary, buzzwords, fts_config FROM dmslite.document_content WHERE id = $1",
+        &[&id, &new_id],
+    ).await?;
+    transaction.execute(
+        "INSERT INTO dmslite.document_pages (id, page, content, method)
+        SELECT $2, page, content, method FROM dmslite.document_pages WHERE id = $1",
+        &[&id, &new_id],
+    ).await?;
+    transaction.execute(
+        "INSERT INTO dmslite.document_amounts (id, position, value, currency)
+        SELECT $2, position, value, currency FROM dmslite.document_amounts WHERE id = $1",
+        &[&id, &new_id],
+    ).await?;
+    transaction.execute(
+        "INSERT INTO dmslite.document_chunks (id, chunk, page, content, model, embedding)
+        SELECT $2, chunk, page, content, model, embedding FROM dmslite.document_chunks WHERE id = $1",
+        &[&id, &new_id],
+    ).await?;
+    transaction.execute(
+        "INSERT INTO dmslite.document_tags (id, tag_id, suggested)
+        SELECT $2, tag_id, suggested FROM dmslite.document_tags WHERE id = $1",
+        &[&id, &new_id],
+    ).await?;
+    transaction.commit().await?;
+    Ok(new_id)
+}
+
+// Store the embedded chunks of the Document with the <id>.
+async fn insert_chunks(client: &impl GenericClient, id: i32, chunks: &[DocumentChunk]) -> Result<(), DbError> {
+    for chunk in chunks {
+        client.execute(
+            "INSERT INTO dmslite.document_chunks (id, chunk, page, content, model, embedding)
+            VALUES ($1, $2, $3, $4, $5, $6)",
+            &[&id, &chunk.chunk, &chunk.page, &chunk.content, &chunk.model, &chunk.embedding],
+        ).await?;
+    }
+    Ok(())
+}
+
+// Replace the embedded chunks of the Document with the <id>, e.g. with those of another model,
+// and record the model even if there are no chunks.
+pub async fn replace_chunks(db: &Database, id: i32, model: &str, chunks: &[DocumentChunk]) -> Result<(), DbError> {
+    let mut client = db.client().await?;
+    let transaction = client.transaction().await?;
+    transaction.execute("DELETE FROM dmslite.document_chunks WHERE id = $1", &[&id]).await?;
+    transaction.execute("UPDATE dmslite.main_table SET embedding_model = $2 WHERE id = $1", &[&id, &model]).await?;
+    insert_chunks(&transaction, id, chunks).await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+// Ids of all Documents not embedded with the model yet.
+pub async fn ids_without_embeddings(db: &Database, model: &str) -> Result<Vec<i32>, DbError> {
+    let rows = db.client().await?.query(
+        "SELECT id FROM dmslite.main_table WHERE embedding_model IS DISTINCT FROM $1 ORDER BY id",
+        &[&model],
+    ).await?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+// Text of every page of the Document with the <id>.
+pub async fn pages(db: &Database, id: i32) -> Result<Vec<DocumentPage>, DbError> {
+    let rows = db.client().await?.query(
+        "SELECT id, page, COALESCE(content, ''), COALESCE(method, 'ocr') FROM dmslite.document_pages WHERE id = $1 ORDER BY page",
+        &[&id],
+    ).await?;
+    Ok(rows.iter().map(|row| DocumentPage { _id: row.get(0), page: row.get(1), content: row.get(2), method: row.get(3) }).collect())
+}
+
+// Set or (with None) remove the document date of the Document with the <id>.
+// Returns false if there is no such Document.
+pub async fn set_document_date(db: &Database, id: i32, date: Option<NaiveDate>) -> Result<bool, DbError> {
+    let updated = db.client().await?.execute(
+        "UPDATE dmslite.main_table SET document_date = $2 WHERE id = $1",
+        &[&id, &date],
+    ).await?;
+    Ok(updated > 0)
+}
+
+// SQL expression of the date the results are shown, filtered and sorted by.
+fn date_column(date: DateField) -> &'static str {
+    match date {
+        DateField::Document => "COALESCE(m.document_date, m.upload_date)",
+        DateField::Upload => "m.upload_date",
+    }
+}
+
+// Table columns a search term is matched against.
+fn columns(field: Option<Field>) -> &'static [&'static str] {
+    match field {
+        None => &["c.content", "c.summary", "c.buzzwords"],
+        Some(Field::Title) => &["m.title"],
+        Some(Field::Content) => &["c.content"],
+        Some(Field::Summary) => &["c.summary"],
+        Some(Field::Buzzwords) => &["c.buzzwords"],
+    }
+}
+
+// Phrases are matched with ILIKE, so escape its wildcards.
+fn like_pattern(phrase: &str) -> String {
+    let escaped = phrase.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+// SQL condition that the term bound to the parameter p matches any of the columns.
+// Fuzzy terms have to be within the sensitivity threshold (parameter s), phrases must appear literally.
+fn term_condition(term: &Term, p: &str, s: &str, columns: &[&str]) -> String {
+    let conditions: Vec<String> = columns.iter().map(|col| {
+        if term.phrase {
+            format!("{} ILIKE {}", col, p)
+        } else {
+            format!("{} <<-> {} < {}", p, col, s)
+        }
+    }).collect();
+    format!("COALESCE({}, false)", conditions.join(" OR "))
+}
+
+// word_similarity distance of the term to the closest of the columns. Phrases have no distance.
+fn term_distance(term: &Term, p: &str, columns: &[&str]) -> Option<String> {
+    if term.phrase {
+        return None;
+    }
+    let distances: Vec<String> = columns.iter().map(|col| format!("{} <<-> {}", p, col)).collect();
+    Some(format!("LEAST({})", distances.join(", ")))
+}
+
+// SQL condition of a filter bound to the parameter p.
+fn filter_condition(filter: &Filter, p: &str, options: &SearchOptions) -> String {
+    let condition = match &filter.kind {
+        FilterKind::Tag(_) => format!("EXISTS (SELECT 1 FROM dmslite.document_tags dt JOIN dmslite.tags t ON t.id = dt.tag_id
+            WHERE dt.id = m.id AND t.name = {})", p),
+        FilterKind::From(_) => format!("EXISTS (SELECT 1 FROM dmslite.correspondents co
+            WHERE co.id = m.correspondent_id AND co.name ILIKE {})", p),
+        FilterKind::Date(..) => format!("({}::text)::daterange @> {}", p, date_column(options.date)),
+    };
+    match filter.negated {
+        true => format!("NOT {}", condition),
+        false => condition,
+    }
+}
+
+// Value of a filter bound as its parameter.
+fn filter_value(filter: &Filter) -> String {
+    match &filter.kind {
+        FilterKind::Tag(name) => name.clone(),
+        FilterKind::From(name) => like_pattern(name),
+        FilterKind::Date(start, end) => format!("[{},{})",
+            start.map(|d| d.to_string()).unwrap_or_default(),
+            end.map(|d| d.to_string()).unwrap_or_default()),
+    }
+}
+
+// Input of websearch_to_tsquery: the terms as words, "quoted phrases" and -excluded words.
+// Alongside the fuzzy search (all false) only the plain terms without a field prefix are used,
+// the others are matched fuzzy anyway.
+fn websearch_text(query: &Query, all: bool) -> String {
+    query.terms.iter()
+        .filter(|t| all || (t.field.is_none() && t.occur == Occur::Should))
+        .map(|t| {
+            let text = match t.phrase {
+                true => format!("\"{}\"", t.text),
+                false => t.text.clone(),
+            };
+            match t.occur {
+                Occur::MustNot => format!("-{}", text),
+                Occur::Should | Occur::Must => text,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Input of websearch_to_tsquery for highlighting the snippets: every word and "quoted phrase"
+// of the required and plain terms, or-ed so that each of them is highlighted on its own.
+fn highlight_text(query: &Query) -> String {
+    query.terms.iter()
+        .filter(|t| t.occur != Occur::MustNot)
+        .flat_map(|t| match t.phrase {
+            true => vec![format!("\"{}\"", t.text)],
+            // "or" is the OR operator of websearch_to_tsquery
+            false => t.text.split_whitespace().filter(|w| !w.eq_ignore_ascii_case("or")).map(String::from).collect(),
+        })
+        .collect::<Vec<_>>()
+        .join(" or ")
+}
+
+// Plain words of the same field are fuzzy matched together as one phrase,
+// so "tax return 2023" is ranked as a whole like a single search term.
+fn combine_should_terms(query: &Query) -> Vec<Term> {
+    let mut terms: Vec<Term> = Vec::new();
+    for term in &query.terms {
+        if term.occur == Occur::Should && !term.phrase {
+            if let Some(t) = terms.iter_mut().find(|t| t.occur == Occur::Should && !t.phrase && t.field == term.field) {
+                t.text.push(' ');
+                t.text.push_str(&term.text);
+                continue;
+            }
+        }
+        terms.push(term.clone());
+    }
+    terms
+}
+
+// Search documents matching the query in the Columns content, summary and buzzwords (or the prefixed field),
 // order them by word_similarity distnce and return all values over sensitivity threshold.
-pub async fn search(search_term: String) -> Result<Vec<SearchResult>, Error> {
+// Required terms must match, excluded terms must not match and at least one of the plain terms has to match.
+// In the fulltext mode the terms are matched by the Postgres full-text search instead, ranked by ts_rank.
+// The combined mode also accepts full-text hits in place of the plain terms and blends both ranks.
+// The semantic mode returns the documents with the chunks nearest to the embedding of the terms in the options,
+// the hybrid mode accepts them in place of the plain terms and blends the distance with the trigram distance.
+// Both ranks are distances: 0 is the best match.
+// Filters restrict the results further, a query of only filters lists all matching documents.
+// For every hit the best matching page is looked up in document_pages, the fields that matched are listed
+// and a snippet of the text with the search words highlighted is returned.
+// The options pick the date that is returned and filtered by, the order of the results and the page of them.
+// Returns the page of results and the total number of results.
+pub async fn search(db: &Database, query: &Query, options: &SearchOptions) -> Result<(Vec<SearchResult>, i64), DbError> {
     let sensitivity: f32 = 0.6;
     let mut results: Vec<SearchResult> = Vec::new();
 
-    let db: Database = Database::init().await.unwrap();
-    tokio::spawn(async move {
-        if let Err(e) = db.connection.expect("Coudlnt find Connection to Psql").await {
-            eprintln!("psql connection error: {}", e);
+    let client = db.client().await?;
+
+    // Bind every term, then every filter, the text search configuration, the full-text query and the highlighted words to its own parameter.
+    // The text search configuration is a parameter, not the fts_config column of each row,
+    // so that the tsquery is a constant and the search_vector index can be used.
+    // The sensitivity comes last and is only bound if a fuzzy term uses it.
+    let terms = match options.mode {
+        SearchMode::Fulltext | SearchMode::Semantic => Vec::new(),
+        SearchMode::Fuzzy | SearchMode::Combined | SearchMode::Hybrid => combine_should_terms(query),
+    };
+    let fulltext = match options.mode {
+        SearchMode::Fuzzy | SearchMode::Semantic | SearchMode::Hybrid => String::new(),
+        SearchMode::Fulltext => websearch_text(query, true),
+        SearchMode::Combined => websearch_text(query, false),
+    };
+    let mut texts: Vec<String> = terms.iter()
+        .map(|t| if t.phrase { like_pattern(&t.text) } else { t.text.clone() })
+        .chain(query.filters.iter().map(filter_value))
+        .collect();
+    let placeholders: Vec<String> = (1..=texts.len()).map(|i| format!("${}", i)).collect();
+    let highlight = highlight_text(query);
+    let mut fts_config = String::new();
+    if !fulltext.is_empty() || !highlight.is_empty() {
+        texts.push(options.fts_config.to_string());
+        fts_config = format!("${}::text::regconfig", texts.len());
+    }
+    let mut tsquery = None;
+    if !fulltext.is_empty() {
+        texts.push(fulltext);
+        tsquery = Some(format!("websearch_to_tsquery({}, ${})", fts_config, texts.len()));
+    }
+    let mut highlight_query = None;
+    if !highlight.is_empty() {
+        texts.push(highlight);
+        highlight_query = Some(format!("(websearch_to_tsquery({0}, ${1}) || websearch_to_tsquery('simple', ${1}))", fts_config, texts.len()));
+    }
+    // The embedding of the terms is bound after the texts, followed by the sensitivity.
+    let embedding = options.embedding.as_ref().filter(|_| options.mode.is_semantic());
+    let e = embedding.map(|_| format!("${}", texts.len() + 1));
+    let s = format!("${}", texts.len() + 1 + embedding.is_some() as usize);
+
+    let mut should = Vec::new();
+    let mut filters = Vec::new();
+    let mut distances = Vec::new();
+    let mut page_conditions = Vec::new();
+    let mut page_distances = Vec::new();
+    let mut field_conditions: Vec<Vec<String>> = vec![Vec::new(); MATCH_FIELDS.len()];
+    for (term, p) in terms.iter().zip(&placeholders) {
+        let cols = columns(term.field);
+        let condition = term_condition(term, p, &s, cols);
+        match term.occur {
+            Occur::Should => should.push(condition),
+            Occur::Must => filters.push(condition),
+            Occur::MustNot => filters.push(format!("NOT {}", condition)),
+        }
+        if term.occur != Occur::MustNot {
+            for ((_, col), conditions) in MATCH_FIELDS.iter().zip(field_conditions.iter_mut()) {
+                if cols.contains(col) {
+                    conditions.push(term_condition(term, p, &s, &[col]));
+                }
+            }
+            distances.extend(term_distance(term, p, cols));
+            if cols.contains(&"c.content") {
+                page_conditions.push(term_condition(term, p, &s, &["p.content"]));
+                page_distances.extend(term_distance(term, p, &["p.content"]));
+            }
+        }
+    }
+    if let Some(tsquery) = &tsquery {
+        let condition = format!("c.search_vector @@ {}", tsquery);
+        match options.mode {
+            SearchMode::Combined if should.is_empty() => {}
+            SearchMode::Combined => should.push(condition),
+            _ => filters.push(condition),
+        }
+        page_conditions.push(format!("to_tsvector({}, p.content) @@ {}", fts_config, tsquery));
+    }
+    // A field matched in the full-text search if any of the words is in it, all words are rarely in a single field.
+    if let (Some(_), Some(highlight_query)) = (&tsquery, &highlight_query) {
+        for ((_, col), conditions) in MATCH_FIELDS.iter().zip(field_conditions.iter_mut()).take(3) {
+            conditions.push(format!("to_tsvector({}, coalesce({}, '')) @@ {}", fts_config, col, highlight_query));
+        }
+    }
+    // The semantic mode returns the nearest documents up to the limit, however far they are.
+    // The hybrid mode takes only near documents that the fuzzy search did not find.
+    if e.is_some() {
+        match options.mode {
+            SearchMode::Hybrid if should.is_empty() => {}
+            SearchMode::Hybrid => should.push(format!("sem.distance <= {}", options.hybrid_max_distance)),
+            _ => filters.push("sem.distance IS NOT NULL".to_string()),
         }
-    });
-    let client = db.client.expect("Psql Cient not found");
+    }
+    if !should.is_empty() {
+        filters.push(format!("({})", should.join(" OR ")));
+    }
+    for (filter, p) in query.filters.iter().zip(&placeholders[terms.len()..]) {
+        filters.push(filter_condition(filter, p, options));
+    }
+    if filters.is_empty() {
+        filters.push("TRUE".to_string());
+    }
+    let trigram_rank = match distances.is_empty() {
+        true => None,
+        false => Some(format!("LEAST({})", distances.join(", "))),
+    };
+    // ts_rank normalized to 0..1 and turned into a distance
+    let fulltext_rank = tsquery.as_ref().map(|tsquery| format!("(1 - ts_rank(c.search_vector, {}, 32))::real", tsquery));
+    // Only one of them, the combined mode has no embedding and the hybrid mode no full-text query
+    let other_rank = match (fulltext_rank, &e) {
+        (Some(fulltext), _) => Some((FULLTEXT_WEIGHT, fulltext)),
+        (None, Some(_)) => Some((SEMANTIC_WEIGHT, "sem.distance".to_string())),
+        (None, None) => None,
+    };
+    let rank = match (trigram_rank, other_rank) {
+        (None, None) => "0::real".to_string(),
+        (Some(rank), None) | (None, Some((_, rank))) => rank,
+        (Some(trigram), Some((weight, other))) => format!("({} * COALESCE({}, 1) + {} * COALESCE({}, 1))::real",
+            weight, other, 1.0 - weight, trigram),
+    };
+    let page = match page_conditions.is_empty() {
+        true => "NULL::integer".to_string(),
+        false => format!("(SELECT page FROM dmslite.document_pages p
+            WHERE p.id = m.id AND ({})
+            ORDER BY {}page ASC
+            LIMIT 1)",
+            page_conditions.join(" OR "),
+            match page_distances.is_empty() {
+                true => String::new(),
+                false => format!("LEAST({}) ASC, ", page_distances.join(", ")),
+            }),
+    };
+    // Best matching chunk of the Document, chunks of a model with another size are skipped
+    let (page, nearest_chunk) = match &e {
+        Some(e) => (format!("COALESCE({}, sem.page)", page), format!("LEFT JOIN LATERAL (
+        SELECT (1 - dmslite.cosine_similarity(ch.embedding, {0}))::real AS distance, ch.page
+        FROM dmslite.document_chunks ch
+        WHERE ch.id = m.id AND cardinality(ch.embedding) = cardinality({0}::real[])
+        ORDER BY distance ASC
+        LIMIT 1) sem ON true", e)),
+        None => (page, String::new()),
+    };
+    let matched: Vec<String> = MATCH_FIELDS.iter().zip(&field_conditions)
+        .filter(|(_, conditions)| !conditions.is_empty())
+        .map(|((name, _), conditions)| format!("CASE WHEN {} THEN '{}' END", conditions.join(" OR "), name))
+        .collect();
+    let fields = match matched.is_empty() {
+        true => "ARRAY[]::text[]".to_string(),
+        false => format!("array_remove(ARRAY[{}], NULL)", matched.join(", ")),
+    };
+    let snippet = match &highlight_query {
+        Some(highlight_query) => format!("ts_headline({},
+            CASE WHEN 'content' = ANY(f.fields) THEN c.content
+                WHEN 'summary' = ANY(f.fields) THEN c.summary
+                WHEN 'buzzwords' = ANY(f.fields) THEN c.buzzwords
+                ELSE c.content END,
+            {}, '{}')", fts_config, highlight_query, SNIPPET_OPTIONS),
+        None => "NULL::text".to_string(),
+    };
+
+    let direction = match options.order {
+        Order::Asc => "ASC",
+        Order::Desc => "DESC",
+    };
+    let order = match options.sort {
+        Sort::Rank => format!("rank {}, m.id ASC", direction),
+        Sort::Date => format!("date {0}, m.id {0}", direction),
+        Sort::Title => format!("lower(m.title) {}, m.id ASC", direction),
+        Sort::Id => format!("m.id {}", direction),
+    };
+    let limit = match options.limit {
+        Some(limit) => limit.to_string(),
+        None => "ALL".to_string(),
+    };
+
+    let sql = format!("SELECT m.id, {} AS rank, m.title, {} AS date, {} AS page, {} AS tags, {} AS correspondent,
+        f.fields, {} AS snippet, COUNT(*) OVER () AS total
+    FROM dmslite.main_table m
+    JOIN dmslite.document_content c ON c.id = m.id
+    CROSS JOIN LATERAL (SELECT {} AS fields) f
+    {}
+    WHERE {}
+    ORDER BY {}
+    LIMIT {} OFFSET {};", rank, date_column(options.date), page, tags::tags_column("m.id"), correspondents::correspondent_column(),
+        snippet, fields, nearest_chunk, filters.join(" AND "), order, limit, options.offset);
+
+    let mut params: Vec<&(dyn ToSql + Sync)> = texts.iter().map(|t| t as &(dyn ToSql + Sync)).collect();
+    if let Some(embedding) = embedding {
+        params.push(embedding);
+    }
+    if terms.iter().any(|t| !t.phrase) {
+        params.push(&sensitivity);
+    }
 
     // Prepare and execute the search query
-    for row in client.query("SELECT DISTINCT main_table.id, subquery.distance, main_table.title, main_table.upload_date
-    FROM (
-        SELECT id, $1 <<-> content AS distance
-        FROM document_content
-        WHERE $1 <<-> content < $2 OR $1 <<-> content = 0
-        UNION
-        SELECT id, $1 <<-> summary AS distance
-        FROM document_content
-        WHERE $1 <<-> summary < $2 OR $1 <<-> summary = 0
-        UNION
-        SELECT id, $1 <<-> buzzwords AS distance
-        FROM document_content
-        WHERE $1 <<-> buzzwords < $2 OR $1 <<-> buzzwords = 0
-    ) AS subquery
-    JOIN main_table ON subquery.id = main_table.id
-    ORDER BY subquery.distance ASC;",
-        &[&search_term, &sensitivity],
-    ).await? {
-        let search_r = SearchResult { _id: row.get(0), rank:row.get(1), title:row.get(2), upload_date:row.get(3)};
+    let mut total = 0;
+    for row in client.query(&sql, &params).await? {
+        total = row.get(9);
+        let search_r = SearchResult { _id: row.get(0), rank:row.get(1), title:row.get(2), date:row.get(3), page:row.get(4), tags:row.get(5), correspondent:row.get(6),
+            fields:row.get(7), snippet:row.get(8)};
         results.push(search_r);
     }
-    Ok(results)
+    Ok((results, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{llm, migrations, query};
+    use tokio::sync::Mutex;
+
+    // Migrations of the test database run one test at a time.
+    static MIGRATED: Mutex<bool> = Mutex::const_new(false);
+
+    // Database of the tests that need Postgres, run them with `cargo test -- --ignored`.
+    // DMSLITE_TEST_DBNAME names a database the tests may write to, the other connection settings
+    // come from the DMSLITE_PSQL_* env vars like for dmslite itself.
+    async fn test_database() -> Database {
+        let dbname = std::env::var("DMSLITE_TEST_DBNAME").expect("DMSLITE_TEST_DBNAME is not set");
+        let mut psql = PsqlConfig { dbname, ..PsqlConfig::default() };
+        let env = |name: &str| std::env::var(format!("DMSLITE_PSQL_{}", name)).ok();
+        if let Some(v) = env("HOST") { psql.host = v }
+        if let Some(v) = env("PORT") { psql.port = v.parse().unwrap() }
+        if let Some(v) = env("USER") { psql.user = v }
+        if let Some(v) = env("PASSWORD") { psql.password = v }
+        let db = Database::init(&psql).unwrap();
+        let mut migrated = MIGRATED.lock().await;
+        if !*migrated {
+            migrations::init(&db).await.unwrap();
+            *migrated = true;
+        }
+        db
+    }
+
+    // Store a document with the text as its only page and a chunk of it for every embedding,
+    // replacing the one of an earlier run.
+    async fn add_document(db: &Database, title: &str, text: &str, date: Option<NaiveDate>, embeddings: &[Vec<f32>]) -> i32 {
+        remove_document(db, title).await;
+        let text = llm::clean_ocr_text(text.to_string());
+        let document = Document {
+            _id: 0, upload_date: NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(), document_date: date,
+            filepath: None, archive_filepath: None, title: title.to_string(), content_hash: format!("test {}", title),
+            correspondent: None, document_type: None, embedding_model: None,
+        };
+        let content = DocumentContent { _id: 0, content: text.clone(), summary: String::new(), buzzwords: String::new(), fts_config: "german".to_string() };
+        let chunks: Vec<DocumentChunk> = embeddings.iter().enumerate()
+            .map(|(i, embedding)| DocumentChunk { _id: 0, chunk: i as i32, page: 1, content: text.clone(), model: "test".to_string(), embedding: embedding.clone() })
+            .collect();
+        let page = DocumentPage { _id: 0, page: 1, content: text, method: "ocr".to_string() };
+        add_to_psql(db, document, content, vec![page], &[], &chunks, &[]).await.unwrap();
+        let row = db.client().await.unwrap().query_one("SELECT id FROM dmslite.main_table WHERE title = $1", &[&title]).await.unwrap();
+        row.get(0)
+    }
+
+    async fn remove_document(db: &Database, title: &str) {
+        db.client().await.unwrap().execute("DELETE FROM dmslite.main_table WHERE title = $1", &[&title]).await.unwrap();
+    }
+
+    fn options(mode: SearchMode, embedding: Option<Vec<f32>>) -> SearchOptions {
+        let mut options = SearchOptions::new(Sort::Rank, "german");
+        options.mode = mode;
+        options.embedding = embedding;
+        options.limit = None;
+        options
+    }
+
+    async fn fulltext(db: &Database, text: &str) -> Vec<SearchResult> {
+        search(db, &query::parse(text).unwrap(), &options(SearchMode::Fulltext, None)).await.unwrap().0
+    }
+
+    // Ids of the results that are among the ids, in the order of the results.
+    fn found(results: &[SearchResult], ids: &[i32]) -> Vec<i32> {
+        results.iter().map(|r| r._id).filter(|id| ids.contains(id)).collect()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the Postgres database in DMSLITE_TEST_DBNAME"]
+    async fn fulltext_finds_umlauts() {
+        let db = test_database().await;
+        let id = add_document(&db, "Umlaute", "Die Größe der\nWohnfläche beträgt 80 m².", None, &[]).await;
+
+        for (text, word) in [("Größe", "Größe"), ("größe", "Größe"), ("Wohnfläche", "Wohnfläche"), ("beträgt", "beträgt")] {
+            let results = fulltext(&db, text).await;
+            let hit = results.iter().find(|r| r._id == id).unwrap_or_else(|| panic!("{} not found", text));
+            assert_eq!(hit.page, Some(1));
+            assert!(hit.snippet.as_deref().unwrap_or_default().contains(&format!("\x02{}\x03", word)), "{:?}", hit.snippet);
+        }
+        assert!(!fulltext(&db, "Groe").await.iter().any(|r| r._id == id));
+
+        remove_document(&db, "Umlaute").await;
+    }
+
+    // The semantic tests embed into their own number of dimensions, the chunks of other documents are skipped then.
+    #[tokio::test]
+    #[ignore = "needs the Postgres database in DMSLITE_TEST_DBNAME"]
+    async fn semantic_returns_the_nearest_however_far() {
+        let db = test_database().await;
+        let near = add_document(&db, "Semantik nah", "Mietvertrag", None, &[vec![1.0, 0.0, 0.0]]).await;
+        let across = add_document(&db, "Semantik quer", "Heizkosten", None, &[vec![0.0, 1.0, 0.0]]).await;
+        let far = add_document(&db, "Semantik fern", "Kaufvertrag", None, &[vec![0.0, -1.0, 0.0], vec![-1.0, 0.0, 0.0]]).await;
+        let ids = [near, across, far];
+
+        let mut options = options(SearchMode::Semantic, Some(vec![1.0, 0.0, 0.0]));
+        let (results, total) = search(&db, &query::parse("Miete").unwrap(), &options).await.unwrap();
+        assert_eq!(found(&results, &ids), ids);
+        assert_eq!(total, 3);
+        assert_eq!(results.iter().map(|r| r.rank).collect::<Vec<_>>(), vec![0.0, 1.0, 1.0]);
+
+        options.limit = Some(2);
+        let (results, total) = search(&db, &query::parse("Miete").unwrap(), &options).await.unwrap();
+        assert_eq!(found(&results, &ids), vec![near, across]);
+        assert_eq!(total, 3);
+
+        for title in ["Semantik nah", "Semantik quer", "Semantik fern"] {
+            remove_document(&db, title).await;
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the Postgres database in DMSLITE_TEST_DBNAME"]
+    async fn hybrid_takes_near_documents_up_to_the_max_distance() {
+        let db = test_database().await;
+        let fuzzy = add_document(&db, "Hybrid Miete", "Mietvertrag der Wohnung", None, &[vec![0.0, -1.0, 0.0, 0.0]]).await;
+        let near = add_document(&db, "Hybrid nah", "Stromrechnung", None, &[vec![1.0, 0.1, 0.0, 0.0]]).await;
+        let across = add_document(&db, "Hybrid quer", "Heizkosten", None, &[vec![0.0, 1.0, 0.0, 0.0]]).await;
+        let ids = [fuzzy, near, across];
+
+        let mut options = options(SearchMode::Hybrid, Some(vec![1.0, 0.0, 0.0, 0.0]));
+        let mut hits = found(&search(&db, &query::parse("Mietvertrag").unwrap(), &options).await.unwrap().0, &ids);
+        hits.sort();
+        assert_eq!(hits, vec![fuzzy, near]);
+
+        options.hybrid_max_distance = 1.5;
+        let mut hits = found(&search(&db, &query::parse("Mietvertrag").unwrap(), &options).await.unwrap().0, &ids);
+        hits.sort();
+        assert_eq!(hits, ids);
+
+        for title in ["Hybrid Miete", "Hybrid nah", "Hybrid quer"] {
+            remove_document(&db, title).await;
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the Postgres database in DMSLITE_TEST_DBNAME"]
+    async fn semantic_filters_without_embedding() {
+        let db = test_database().await;
+        let id = add_document(&db, "Nur Filter", "Kontoauszug", NaiveDate::from_ymd_opt(1999, 12, 31), &[]).await;
+
+        let (results, total) = search(&db, &query::parse("date:1999-12-31").unwrap(), &options(SearchMode::Semantic, None)).await.unwrap();
+        assert_eq!(found(&results, &[id]), vec![id]);
+        assert_eq!(total, 1);
+
+        remove_document(&db, "Nur Filter").await;
+    }
 }
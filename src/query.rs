@@ -0,0 +1,417 @@
+// Parser for the search query language.
+//
+// Supported syntax:
+// - `tax return 2023`   plain words, fuzzy matched together as one phrase
+// - `"tax return"`      quoted phrase, must appear literally (case-insensitive)
+// - `+word` `-word`     required and excluded terms (also `+"a phrase"`)
+// - `title:word`        restrict a term to one field (title, content, summary, buzzwords)
+// - `tag:name`          only documents with the tag (also `tag:"two words"`, `-tag:name` excludes it)
+// - `from:name`         only documents whose correspondent contains the name (also `from:"two words"`)
+// - `date:2023`         only documents dated in the year, month (`date:2023-03`) or day (`date:2023-03-12`)
+// - `after:2023-01-01`  only documents dated on or after the day (also a year or month: `after:2023`)
+// - `before:2023-01-01` only documents dated before the day
+// - `year:2023`         only documents dated in the year
+
+use chrono::{Months, NaiveDate};
+
+use crate::error::DmsError;
+
+// Document fields a search term can be restricted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Title,
+    Content,
+    Summary,
+    Buzzwords,
+}
+
+impl Field {
+    fn from_prefix(prefix: &str) -> Option<Field> {
+        match prefix.to_lowercase().as_str() {
+            "title" => Some(Field::Title),
+            "content" => Some(Field::Content),
+            "summary" => Some(Field::Summary),
+            "buzzwords" => Some(Field::Buzzwords),
+            _ => None,
+        }
+    }
+}
+
+// Restriction of the result set by document metadata. Filters do not take part in the ranking.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterKind {
+    // `tag:name`
+    Tag(String),
+    // `from:name`
+    From(String),
+    // `date:2023`, `after:`, `before:`, `year:`: dates from the first day (if set)
+    // up to the day before the second (if set)
+    Date(Option<NaiveDate>, Option<NaiveDate>),
+}
+
+impl FilterKind {
+    // The filter of the prefix, None for field prefixes. Fails on values that are no date for the date filters.
+    fn from_prefix(prefix: &str, value: &str) -> Result<Option<FilterKind>, DmsError> {
+        let date = || match prefix {
+            "year" if value.len() != 4 => None,
+            _ => period(value),
+        }.ok_or(DmsError::InvalidFilter { prefix: prefix.to_string(), value: value.to_string() });
+        let kind = match prefix {
+            "tag" => FilterKind::Tag(crate::tags::normalize(value)),
+            "from" => FilterKind::From(value.to_string()),
+            "date" | "year" => date().map(|(start, end)| FilterKind::Date(Some(start), Some(end)))?,
+            "after" => date().map(|(start, _)| FilterKind::Date(Some(start), None))?,
+            "before" => date().map(|(start, _)| FilterKind::Date(None, Some(start)))?,
+            _ => return Ok(None),
+        };
+        Ok(Some(kind))
+    }
+}
+
+// First day of the year, month or day and the first day after it.
+fn period(value: &str) -> Option<(NaiveDate, NaiveDate)> {
+    let parts: Vec<&str> = value.split('-').collect();
+    match parts[..] {
+        [year] if year.len() == 4 => {
+            let start = NaiveDate::from_ymd_opt(year.parse().ok()?, 1, 1)?;
+            Some((start, start.checked_add_months(Months::new(12))?))
+        }
+        [year, month] if year.len() == 4 => {
+            let start = NaiveDate::from_ymd_opt(year.parse().ok()?, month.parse().ok()?, 1)?;
+            Some((start, start.checked_add_months(Months::new(1))?))
+        }
+        _ => {
+            let day = crate::dates::parse(value)?;
+            Some((day, day.succ_opt()?))
+        }
+    }
+}
+
+// Which date of a document is shown, filtered and sorted by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DateField {
+    // Date found in the document text, the upload date if none was found
+    #[default]
+    Document,
+    Upload,
+}
+
+// How the terms are matched.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SearchMode {
+    // Trigram word similarity, tolerates typos and OCR errors
+    Fuzzy,
+    // Postgres full-text search with stemming. Field prefixes are ignored
+    Fulltext,
+    // Documents found by either, ranked by a blend of both
+    #[default]
+    Combined,
+    // Nearest documents by the cosine similarity of their embeddings to the embedding of the terms
+    Semantic,
+    // Documents found by the fuzzy or the semantic search, ranked by a blend of both
+    Hybrid,
+}
+
+impl SearchMode {
+    // Modes that need the embedding of the terms.
+    pub fn is_semantic(&self) -> bool {
+        matches!(self, SearchMode::Semantic | SearchMode::Hybrid)
+    }
+}
+
+// Results shown at once, unless another limit is given.
+pub const DEFAULT_LIMIT: u32 = 50;
+// Chunks farther from the query (1 - cosine similarity) do not make a document a hit of the hybrid search on their own.
+pub const DEFAULT_HYBRID_MAX_DISTANCE: f32 = 0.5;
+
+// What the results are sorted by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Sort {
+    Rank,
+    Date,
+    Title,
+    Id,
+}
+
+impl Sort {
+    // Best match, newest date, title from A and lowest id first.
+    pub fn default_order(&self) -> Order {
+        match self {
+            Sort::Date => Order::Desc,
+            Sort::Rank | Sort::Title | Sort::Id => Order::Asc,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+// How the results of a query are presented.
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+    pub mode: SearchMode,
+    // Text search configuration of the full-text query, e.g. "german"
+    pub fts_config: &'static str,
+    // Embedding of the terms for the semantic modes
+    pub embedding: Option<Vec<f32>>,
+    // Semantic distance up to which the nearest chunk alone makes a hit in the hybrid mode
+    pub hybrid_max_distance: f32,
+    pub date: DateField,
+    pub sort: Sort,
+    pub order: Order,
+    // None returns all results
+    pub limit: Option<u32>,
+    pub offset: u32,
+}
+
+impl SearchOptions {
+    // Defaults of the interactive shell: the document date, the default order and the first page.
+    pub fn new(sort: Sort, fts_config: &'static str) -> SearchOptions {
+        SearchOptions { mode: SearchMode::default(), fts_config, embedding: None, hybrid_max_distance: DEFAULT_HYBRID_MAX_DISTANCE, date: DateField::Document, sort, order: sort.default_order(), limit: Some(DEFAULT_LIMIT), offset: 0 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    pub kind: FilterKind,
+    // `-tag:name`
+    pub negated: bool,
+}
+
+// Prefixes of fields and filters, anything else followed by a colon is part of the word.
+fn is_prefix(prefix: &str) -> bool {
+    Field::from_prefix(prefix).is_some() || matches!(prefix.to_lowercase().as_str(), "tag" | "from" | "date" | "after" | "before" | "year")
+}
+
+// How a term has to occur in a document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Occur {
+    // Plain term, ranks documents but is not required
+    Should,
+    // `+term`
+    Must,
+    // `-term`
+    MustNot,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Term {
+    pub text: String,
+    // Quoted phrase, matched literally instead of fuzzy
+    pub phrase: bool,
+    // None searches the default fields content, summary and buzzwords
+    pub field: Option<Field>,
+    pub occur: Occur,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Query {
+    pub terms: Vec<Term>,
+    pub filters: Vec<Filter>,
+}
+
+impl Query {
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty() && self.filters.is_empty()
+    }
+
+    // The words of all terms that are not excluded, e.g. to embed them for the semantic search.
+    pub fn text(&self) -> String {
+        self.terms.iter()
+            .filter(|t| t.occur != Occur::MustNot)
+            .map(|t| t.text.as_str())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+// Split the raw query into terms and filters. Unknown prefixes are kept as part of the word.
+// Fails on a date filter whose value is no date, e.g. `after:yesterday`.
+pub fn parse(input: &str) -> Result<Query, DmsError> {
+    let mut terms = Vec::new();
+    let mut filters = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else { break };
+
+        let occur = match first {
+            '+' => { chars.next(); Occur::Must }
+            '-' => { chars.next(); Occur::MustNot }
+            _ => Occur::Should,
+        };
+
+        // Read up to the next whitespace, or to the closing quote if the token opens one.
+        let mut token = String::new();
+        let mut phrase = false;
+        let mut prefix = None;
+        while let Some(c) = chars.next() {
+            if c == '"' {
+                if let Some(p) = token.strip_suffix(':') {
+                    if is_prefix(p) {
+                        prefix = Some(p.to_lowercase());
+                        token.clear();
+                    }
+                }
+                if token.is_empty() {
+                    phrase = true;
+                    token.extend(chars.by_ref().take_while(|&c| c != '"'));
+                    break;
+                }
+            }
+            if c.is_whitespace() {
+                break;
+            }
+            token.push(c);
+        }
+
+        if !phrase {
+            if let Some((p, rest)) = token.split_once(':') {
+                if is_prefix(p) {
+                    prefix = Some(p.to_lowercase());
+                    token = rest.to_string();
+                }
+            }
+        }
+
+        let text = token.trim().to_string();
+        if text.is_empty() {
+            continue;
+        }
+        let prefix = prefix.as_deref();
+        let filter = match prefix {
+            Some(p) => FilterKind::from_prefix(p, &text)?,
+            None => None,
+        };
+        match filter {
+            Some(kind) => filters.push(Filter { kind, negated: occur == Occur::MustNot }),
+            None => terms.push(Term { text, phrase, field: prefix.and_then(Field::from_prefix), occur }),
+        }
+    }
+
+    Ok(Query { terms, filters })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn term(text: &str, phrase: bool, field: Option<Field>, occur: Occur) -> Term {
+        Term { text: text.to_string(), phrase, field, occur }
+    }
+
+    #[test]
+    fn plain_words() {
+        let query = parse("tax  return 2023").unwrap();
+        assert_eq!(query.terms, vec![
+            term("tax", false, None, Occur::Should),
+            term("return", false, None, Occur::Should),
+            term("2023", false, None, Occur::Should),
+        ]);
+        assert!(query.filters.is_empty());
+    }
+
+    #[test]
+    fn empty_query() {
+        assert!(parse("").unwrap().is_empty());
+        assert!(parse("   + - ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn quoted_phrases() {
+        let query = parse(r#"rent +"lease agreement" -"first draft""#).unwrap();
+        assert_eq!(query.terms, vec![
+            term("rent", false, None, Occur::Should),
+            term("lease agreement", true, None, Occur::Must),
+            term("first draft", true, None, Occur::MustNot),
+        ]);
+    }
+
+    #[test]
+    fn unclosed_quote_runs_to_the_end() {
+        let query = parse(r#"rent "lease agreement"#).unwrap();
+        assert_eq!(query.terms[1], term("lease agreement", true, None, Occur::Should));
+    }
+
+    #[test]
+    fn field_prefixes() {
+        let query = parse(r#"Title:invoice +summary:"car insurance" -content:draft buzzwords:tax"#).unwrap();
+        assert_eq!(query.terms, vec![
+            term("invoice", false, Some(Field::Title), Occur::Should),
+            term("car insurance", true, Some(Field::Summary), Occur::Must),
+            term("draft", false, Some(Field::Content), Occur::MustNot),
+            term("tax", false, Some(Field::Buzzwords), Occur::Should),
+        ]);
+    }
+
+    #[test]
+    fn unknown_prefix_is_part_of_the_word() {
+        let query = parse("foo:bar http://example.org").unwrap();
+        assert_eq!(query.terms, vec![
+            term("foo:bar", false, None, Occur::Should),
+            term("http://example.org", false, None, Occur::Should),
+        ]);
+        assert!(query.filters.is_empty());
+    }
+
+    #[test]
+    fn tag_filters() {
+        let query = parse(r#"tag:Insurance -tag:draft tag:"Tax  Return""#).unwrap();
+        assert!(query.terms.is_empty());
+        assert_eq!(query.filters, vec![
+            Filter { kind: FilterKind::Tag("insurance".to_string()), negated: false },
+            Filter { kind: FilterKind::Tag("draft".to_string()), negated: true },
+            Filter { kind: FilterKind::Tag("tax return".to_string()), negated: false },
+        ]);
+    }
+
+    #[test]
+    fn from_filter() {
+        let query = parse(r#"from:"Stadtwerke Köln" rent"#).unwrap();
+        assert_eq!(query.filters, vec![Filter { kind: FilterKind::From("Stadtwerke Köln".to_string()), negated: false }]);
+        assert_eq!(query.terms, vec![term("rent", false, None, Occur::Should)]);
+    }
+
+    fn day(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    fn date_kinds(input: &str) -> Vec<FilterKind> {
+        parse(input).unwrap().filters.into_iter().map(|f| f.kind).collect()
+    }
+
+    #[test]
+    fn date_filters() {
+        assert_eq!(date_kinds("date:2023 date:2023-03 date:12.03.2023"), vec![
+            FilterKind::Date(Some(day(2023, 1, 1)), Some(day(2024, 1, 1))),
+            FilterKind::Date(Some(day(2023, 3, 1)), Some(day(2023, 4, 1))),
+            FilterKind::Date(Some(day(2023, 3, 12)), Some(day(2023, 3, 13))),
+        ]);
+    }
+
+    #[test]
+    fn range_filters() {
+        assert_eq!(date_kinds("after:2023-02 before:2024-01-01 after:2023 year:2022"), vec![
+            FilterKind::Date(Some(day(2023, 2, 1)), None),
+            FilterKind::Date(None, Some(day(2024, 1, 1))),
+            FilterKind::Date(Some(day(2023, 1, 1)), None),
+            FilterKind::Date(Some(day(2022, 1, 1)), Some(day(2023, 1, 1))),
+        ]);
+    }
+
+    #[test]
+    fn text_skips_excluded_terms() {
+        assert_eq!(parse("rent +lease -draft tag:home").unwrap().text(), "rent lease");
+    }
+
+    #[test]
+    fn invalid_date_filters() {
+        for input in ["date:2023-13", "after:yesterday", "before:32.01.2023", "year:23", "year:2023-03"] {
+            assert!(matches!(parse(input), Err(DmsError::InvalidFilter { .. })), "{}", input);
+        }
+    }
+}
@@ -1,11 +1,30 @@
 use chrono::NaiveDate;
+use clap::{Args, ValueEnum};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::query::DEFAULT_HYBRID_MAX_DISTANCE;
 
 // Postgres main_table equivalent for Rust
+// filepath is the stored original, None if it was replaced by the searchable archive PDF.
 pub struct Document {
     pub _id: i32,
     pub upload_date: NaiveDate,
-    pub filepath: String,
-    pub title: String
+    // Date found in the text of the Document
+    pub document_date: Option<NaiveDate>,
+    pub filepath: Option<String>,
+    pub archive_filepath: Option<String>,
+    pub title: String,
+    // SHA-256 of the consumed file
+    pub content_hash: String,
+    // Proposed sender, not confirmed yet
+    pub correspondent: Option<String>,
+    // Kind of the Document named by the LLM, e.g. "Rechnung" or "invoice"
+    pub document_type: Option<String>,
+    // Model the pages were embedded with, None if not embedded yet
+    pub embedding_model: Option<String>
 }
 
 // Postgres Document_content equivalent for Rust
@@ -13,7 +32,38 @@ pub struct DocumentContent {
     pub _id: i32,
     pub content: String,
     pub summary: String,
-    pub buzzwords: String
+    pub buzzwords: String,
+    // Postgres text search configuration of the language, e.g. "german"
+    pub fts_config: String
+}
+
+// Postgres document_pages equivalent for Rust. Pages are counted from 1.
+pub struct DocumentPage {
+    pub _id: i32,
+    pub page: i32,
+    pub content: String,
+    // "text_layer" or "ocr"
+    pub method: String
+}
+
+// Postgres document_amounts equivalent for Rust. Amounts are counted from 1 in the order of the LLM.
+pub struct DocumentAmount {
+    pub _id: i32,
+    pub position: i32,
+    pub value: f64,
+    // Currency code, e.g. "EUR"
+    pub currency: String
+}
+
+// Postgres document_chunks equivalent for Rust. Chunks are counted from 1 over all pages.
+pub struct DocumentChunk {
+    pub _id: i32,
+    pub chunk: i32,
+    pub page: i32,
+    pub content: String,
+    // Ollama model that computed the embedding
+    pub model: String,
+    pub embedding: Vec<f32>
 }
 
 // Postgres Search result equivalent for Rust
@@ -21,18 +71,452 @@ pub struct DocumentContent {
 pub struct SearchResult {
     pub _id: i32,
     pub title: String,
-    pub upload_date: NaiveDate,
-    pub rank: f32
+    // Document or upload date, as requested in the search options
+    pub date: NaiveDate,
+    pub rank: f32,
+    // Best matching page, if the hit came from the page contents
+    pub page: Option<i32>,
+    // Suggested tags end with "?"
+    pub tags: Vec<String>,
+    // Ends with "?" if not confirmed
+    pub correspondent: Option<String>,
+    // Fields the search terms matched in: content, summary, buzzwords or title
+    pub fields: Vec<String>,
+    // Text around the matches, the matched words enclosed in \x02 and \x03
+    pub snippet: Option<String>
 }
 
 
-// Settings for the Postgres DB and the Dirs
-pub mod settings {
-    pub const CONSUME_PATH: &str = "/home/lennart/DMSLite/consume/";
-    pub const STORAGE_PATH: &str = "/home/lennart/DMSLite/storage/";
-    pub const PSQL_HOST: &str = "localhost";
-    pub const PSQL_USER: &str = "dmslite";
-    pub const PSQL_PASSWD: &str = "dmslite";
-    pub const PSQL_DBNAME: &str = "dmslite";
-    pub const TESSERACT_LANG: &str = "deu";
-}
\ No newline at end of file
+// Errors that can occur while loading and validating the configuration.
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Cant read config file {path}: {source}")]
+    Read { path: PathBuf, source: std::io::Error },
+    #[error("Invalid config file {path}: {source}")]
+    Parse { path: PathBuf, source: toml::de::Error },
+    #[error("`{0}` is not set. Set it in the config file, via the env var or the command line flag.")]
+    NotSet(&'static str),
+    #[error("`{0}` must be an absolute path, got {1:?}")]
+    NotAbsolute(&'static str, PathBuf),
+    #[error("`{0}` points to {1:?}, which is not an existing directory")]
+    NotADirectory(&'static str, PathBuf),
+    #[error("`{0}` must be a server URL like http://127.0.0.1:11434, got {1:?}")]
+    InvalidUrl(&'static str, String),
+}
+
+// What to do with the searchable PDF created by Tesseract's pdf renderer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveMode {
+    // Only store the original
+    #[default]
+    Off,
+    // Store the searchable PDF next to the original
+    Alongside,
+    // Store only the searchable PDF
+    Replace,
+}
+
+// What to do with a consumed file that is byte-identical to a stored Document.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateMode {
+    // Set the file aside into the duplicates folder of the consume dir
+    #[default]
+    Skip,
+    // Drop the file, but add a new Document sharing the stored files of the existing one
+    Link,
+}
+
+// Settings for the Postgres DB
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PsqlConfig {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub password: String,
+    pub dbname: String,
+}
+
+impl Default for PsqlConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: 5432,
+            user: "dmslite".to_string(),
+            password: "dmslite".to_string(),
+            dbname: "dmslite".to_string(),
+        }
+    }
+}
+
+// Server software of the LLM.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LlmBackendKind {
+    #[default]
+    Ollama,
+    // Servers with the OpenAI API (/v1/chat/completions), e.g. llama.cpp server, LM Studio or vLLM
+    Openai,
+    // Deterministic answers without a server, for tests
+    Mock,
+}
+
+// How the metadata of a consumed document is generated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExtractionMode {
+    // A single request answered as JSON: title, summary, keywords, document type, date, correspondent and amounts
+    #[default]
+    Json,
+    // Separate requests for the summary, the buzzwords, the title and the correspondent
+    Separate,
+}
+
+// Settings of one LLM task, unset values fall back to the [llm] section and the defaults of the task.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LlmTaskConfig {
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    // System prompt, the one of the prompt set of the language if not set
+    pub system: Option<String>,
+}
+
+// Settings for the LLM
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LlmConfig {
+    pub backend: LlmBackendKind,
+    // Server of the backend (e.g. "http://127.0.0.1:11434"). For Ollama OLLAMA_HOST or the local default if not set,
+    // for OpenAI-compatible servers http://127.0.0.1:8080
+    pub url: Option<String>,
+    // Bearer token of OpenAI-compatible servers that require one
+    pub api_key: Option<String>,
+    // Model of all tasks without an own model, the models of the Modelfiles (doc_summarizer, ...) if not set
+    pub model: Option<String>,
+    // Language of the prompt set ("deu" or "eng"), the one of tesseract_lang if not set
+    pub language: Option<String>,
+    pub extraction: ExtractionMode,
+    // Context window in tokens of the models without an own one, 2048 (the Ollama default) if not set
+    pub context_window: Option<u32>,
+    // Context windows of single models, e.g. "gemma:2b" = 8192
+    pub context_windows: HashMap<String, u32>,
+    pub extract: LlmTaskConfig,
+    // Summaries of the chunks of documents too long for the context window
+    pub chunk: LlmTaskConfig,
+    pub summary: LlmTaskConfig,
+    pub buzzwords: LlmTaskConfig,
+    pub title: LlmTaskConfig,
+    pub correspondent: LlmTaskConfig,
+    pub answer: LlmTaskConfig,
+}
+
+// Runtime configuration of DMSLite.
+// Values are taken from (lowest to highest priority):
+// 1. the defaults below
+// 2. the TOML config file ($XDG_CONFIG_HOME/dmslite/config.toml by default)
+// 3. DMSLITE_* env vars
+// 4. command line flags
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub consume_path: PathBuf,
+    pub storage_path: PathBuf,
+    pub tesseract_lang: String,
+    pub archive: ArchiveMode,
+    pub duplicates: DuplicateMode,
+    // Ollama model for the semantic search (e.g. "nomic-embed-text"), no embeddings are computed without it
+    pub embedding_model: Option<String>,
+    // Semantic distance (1 - cosine similarity) up to which the nearest chunk alone makes a document a hit of the hybrid search
+    pub hybrid_max_distance: f32,
+    pub llm: LlmConfig,
+    pub psql: PsqlConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            consume_path: PathBuf::new(),
+            storage_path: PathBuf::new(),
+            tesseract_lang: "deu".to_string(),
+            archive: ArchiveMode::default(),
+            duplicates: DuplicateMode::default(),
+            embedding_model: None,
+            hybrid_max_distance: DEFAULT_HYBRID_MAX_DISTANCE,
+            llm: LlmConfig::default(),
+            psql: PsqlConfig::default(),
+        }
+    }
+}
+
+// Command line flags (and their env var equivalents) overriding the config file.
+#[derive(Debug, Default, Args)]
+pub struct ConfigArgs {
+    /// Path to the config file [default: $XDG_CONFIG_HOME/dmslite/config.toml]
+    #[arg(long, env = "DMSLITE_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+    /// Absolute path of the folder to consume documents from
+    #[arg(long, env = "DMSLITE_CONSUME_PATH", global = true)]
+    pub consume_path: Option<PathBuf>,
+    /// Absolute path of the long-time storage folder
+    #[arg(long, env = "DMSLITE_STORAGE_PATH", global = true)]
+    pub storage_path: Option<PathBuf>,
+    /// Tesseract language flag (e.g. "eng" or "deu")
+    #[arg(long, env = "DMSLITE_TESSERACT_LANG", global = true)]
+    pub tesseract_lang: Option<String>,
+    /// Store a searchable PDF of OCRed documents
+    #[arg(long, value_enum, env = "DMSLITE_ARCHIVE", global = true)]
+    pub archive: Option<ArchiveMode>,
+    /// What to do with files that are already stored
+    #[arg(long, value_enum, env = "DMSLITE_DUPLICATES", global = true)]
+    pub duplicates: Option<DuplicateMode>,
+    /// Ollama embedding model for the semantic search (e.g. "nomic-embed-text")
+    #[arg(long, env = "DMSLITE_EMBEDDING_MODEL", global = true)]
+    pub embedding_model: Option<String>,
+    /// Semantic distance up to which a document is a hit of the hybrid search without matching the query words
+    #[arg(long, env = "DMSLITE_HYBRID_MAX_DISTANCE", global = true)]
+    pub hybrid_max_distance: Option<f32>,
+    /// Server software of the LLM
+    #[arg(long, value_enum, env = "DMSLITE_LLM_BACKEND", global = true)]
+    pub llm_backend: Option<LlmBackendKind>,
+    /// API key of an OpenAI-compatible LLM server
+    #[arg(long, env = "DMSLITE_LLM_API_KEY", hide_env_values = true, global = true)]
+    pub llm_api_key: Option<String>,
+    /// Server of the LLM (e.g. "http://127.0.0.1:11434")
+    #[arg(long, env = "DMSLITE_LLM_URL", global = true)]
+    pub llm_url: Option<String>,
+    /// LLM model of all tasks without an own model in the config file
+    #[arg(long, env = "DMSLITE_LLM_MODEL", global = true)]
+    pub llm_model: Option<String>,
+    /// Postgres host
+    #[arg(long, env = "DMSLITE_PSQL_HOST", global = true)]
+    pub psql_host: Option<String>,
+    /// Postgres port
+    #[arg(long, env = "DMSLITE_PSQL_PORT", global = true)]
+    pub psql_port: Option<u16>,
+    /// Postgres user
+    #[arg(long, env = "DMSLITE_PSQL_USER", global = true)]
+    pub psql_user: Option<String>,
+    /// Postgres password
+    #[arg(long, env = "DMSLITE_PSQL_PASSWORD", hide_env_values = true, global = true)]
+    pub psql_password: Option<String>,
+    /// Postgres database name
+    #[arg(long, env = "DMSLITE_PSQL_DBNAME", global = true)]
+    pub psql_dbname: Option<String>,
+}
+
+// Default location of the config file.
+pub fn default_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("dmslite").join("config.toml"))
+}
+
+impl Config {
+    // Build the Config from the config file and apply the overrides of args on top.
+    // A missing config file is only an error if its path was given explicitly.
+    pub fn load(args: &ConfigArgs) -> Result<Config, ConfigError> {
+        let mut config = match (&args.config, default_config_path()) {
+            (Some(path), _) => Config::from_file(path)?,
+            (None, Some(path)) if path.exists() => Config::from_file(&path)?,
+            _ => Config::default(),
+        };
+
+        if let Some(v) = &args.consume_path { config.consume_path = v.clone() }
+        if let Some(v) = &args.storage_path { config.storage_path = v.clone() }
+        if let Some(v) = &args.tesseract_lang { config.tesseract_lang = v.clone() }
+        if let Some(v) = args.archive { config.archive = v }
+        if let Some(v) = args.duplicates { config.duplicates = v }
+        if let Some(v) = &args.embedding_model { config.embedding_model = Some(v.clone()) }
+        if let Some(v) = args.hybrid_max_distance { config.hybrid_max_distance = v }
+        if let Some(v) = args.llm_backend { config.llm.backend = v }
+        if let Some(v) = &args.llm_api_key { config.llm.api_key = Some(v.clone()) }
+        if let Some(v) = &args.llm_url { config.llm.url = Some(v.clone()) }
+        if let Some(v) = &args.llm_model { config.llm.model = Some(v.clone()) }
+        if let Some(v) = &args.psql_host { config.psql.host = v.clone() }
+        if let Some(v) = args.psql_port { config.psql.port = v }
+        if let Some(v) = &args.psql_user { config.psql.user = v.clone() }
+        if let Some(v) = &args.psql_password { config.psql.password = v.clone() }
+        if let Some(v) = &args.psql_dbname { config.psql.dbname = v.clone() }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Postgres text search configuration matching the Tesseract language, used for stemming.
+    // For several languages ("deu+eng") the first one is used, unknown languages are not stemmed.
+    pub fn fts_config(&self) -> &'static str {
+        match self.tesseract_lang.split('+').next().unwrap_or_default() {
+            "deu" => "german",
+            "eng" => "english",
+            "fra" => "french",
+            "spa" => "spanish",
+            "ita" => "italian",
+            "por" => "portuguese",
+            "nld" => "dutch",
+            "dan" => "danish",
+            "swe" => "swedish",
+            "nor" => "norwegian",
+            "fin" => "finnish",
+            "hun" => "hungarian",
+            "rus" => "russian",
+            "tur" => "turkish",
+            _ => "simple",
+        }
+    }
+
+    fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let raw = fs::read_to_string(path)
+            .map_err(|source| ConfigError::Read { path: path.to_owned(), source })?;
+        toml::from_str(&raw)
+            .map_err(|source| ConfigError::Parse { path: path.to_owned(), source })
+    }
+
+    // Both folders must be absolute paths to existing directories, the LLM server a valid URL.
+    fn validate(&self) -> Result<(), ConfigError> {
+        validate_dir("consume_path", &self.consume_path)?;
+        validate_dir("storage_path", &self.storage_path)?;
+        if let Some(url) = &self.llm.url {
+            parse_url("llm.url", url)?;
+        }
+        Ok(())
+    }
+}
+
+// URL of a server: http or https with a host.
+pub fn parse_url(name: &'static str, url: &str) -> Result<reqwest::Url, ConfigError> {
+    let invalid = || ConfigError::InvalidUrl(name, url.to_string());
+    let parsed = reqwest::Url::parse(url).map_err(|_| invalid())?;
+    match (parsed.scheme(), parsed.host_str()) {
+        ("http" | "https", Some(_)) => Ok(parsed),
+        _ => Err(invalid()),
+    }
+}
+
+fn validate_dir(name: &'static str, path: &Path) -> Result<(), ConfigError> {
+    if path.as_os_str().is_empty() {
+        return Err(ConfigError::NotSet(name));
+    }
+    if !path.is_absolute() {
+        return Err(ConfigError::NotAbsolute(name, path.to_owned()));
+    }
+    if !path.is_dir() {
+        return Err(ConfigError::NotADirectory(name, path.to_owned()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    // The config flags as the command line parses them, with their env vars.
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        args: ConfigArgs,
+    }
+
+    // Config file with the folders in a new temp dir.
+    fn config_file(dir: &Path, extra: &str) -> PathBuf {
+        for folder in ["consume", "storage"] {
+            fs::create_dir_all(dir.join(folder)).unwrap();
+        }
+        let path = dir.join("config.toml");
+        let text = format!(
+            "consume_path = {:?}\nstorage_path = {:?}\n{}",
+            dir.join("consume"), dir.join("storage"), extra
+        );
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn load(path: &Path) -> Result<Config, ConfigError> {
+        Config::load(&ConfigArgs { config: Some(path.to_owned()), ..ConfigArgs::default() })
+    }
+
+    #[test]
+    fn load_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = load(&config_file(dir.path(), "tesseract_lang = \"eng\"\nhybrid_max_distance = 0.8\n[psql]\nport = 5433\n")).unwrap();
+        assert_eq!(config.consume_path, dir.path().join("consume"));
+        assert_eq!(config.tesseract_lang, "eng");
+        assert_eq!(config.hybrid_max_distance, 0.8);
+        assert_eq!(config.psql.port, 5433);
+        assert_eq!(config.psql.host, PsqlConfig::default().host);
+    }
+
+    #[test]
+    fn not_set() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, "tesseract_lang = \"eng\"\n").unwrap();
+        let e = load(&path).unwrap_err();
+        assert!(matches!(e, ConfigError::NotSet("consume_path")));
+        assert!(e.to_string().contains("Set it in the config file, via the env var or the command line flag"));
+    }
+
+    #[test]
+    fn not_absolute() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = config_file(dir.path(), "");
+        let args = ConfigArgs { config: Some(path), storage_path: Some(PathBuf::from("storage")), ..ConfigArgs::default() };
+        let e = Config::load(&args).unwrap_err();
+        assert!(matches!(&e, ConfigError::NotAbsolute("storage_path", p) if p == Path::new("storage")));
+        assert_eq!(e.to_string(), "`storage_path` must be an absolute path, got \"storage\"");
+    }
+
+    #[test]
+    fn not_a_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = config_file(dir.path(), "");
+        fs::remove_dir(dir.path().join("consume")).unwrap();
+        let e = load(&path).unwrap_err();
+        assert!(matches!(e, ConfigError::NotADirectory("consume_path", _)));
+        assert!(e.to_string().ends_with("which is not an existing directory"));
+        // A file is no directory either
+        let args = ConfigArgs { config: Some(path.clone()), consume_path: Some(path), ..ConfigArgs::default() };
+        assert!(matches!(Config::load(&args), Err(ConfigError::NotADirectory("consume_path", _))));
+    }
+
+    #[test]
+    fn unknown_field() {
+        let dir = tempfile::tempdir().unwrap();
+        let e = load(&config_file(dir.path(), "tesseract_language = \"eng\"\n")).unwrap_err();
+        assert!(matches!(e, ConfigError::Parse { .. }));
+        assert!(e.to_string().contains("tesseract_language"), "{}", e);
+    }
+
+    #[test]
+    fn missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(load(&dir.path().join("missing.toml")), Err(ConfigError::Read { .. })));
+    }
+
+    #[test]
+    fn flag_overrides_env_overrides_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = config_file(dir.path(), "tesseract_lang = \"eng\"\n");
+        let config_flag = format!("--config={}", path.display());
+
+        let cli = Cli::try_parse_from(["dmslite", config_flag.as_str()]).unwrap();
+        assert_eq!(Config::load(&cli.args).unwrap().tesseract_lang, "eng");
+
+        // Only this test uses the env var
+        std::env::set_var("DMSLITE_TESSERACT_LANG", "fra");
+        let from_env = Cli::try_parse_from(["dmslite", config_flag.as_str()]).unwrap();
+        let from_flag = Cli::try_parse_from(["dmslite", config_flag.as_str(), "--tesseract-lang", "deu+eng"]).unwrap();
+        std::env::remove_var("DMSLITE_TESSERACT_LANG");
+        assert_eq!(Config::load(&from_env.args).unwrap().tesseract_lang, "fra");
+        assert_eq!(Config::load(&from_flag.args).unwrap().tesseract_lang, "deu+eng");
+    }
+
+    #[test]
+    fn invalid_llm_url() {
+        let dir = tempfile::tempdir().unwrap();
+        let e = load(&config_file(dir.path(), "[llm]\nurl = \"localhost:11434\"\n")).unwrap_err();
+        assert!(matches!(e, ConfigError::InvalidUrl("llm.url", _)));
+        assert!(load(&config_file(dir.path(), "[llm]\nurl = \"http://localhost:11434/\"\n")).is_ok());
+    }
+}
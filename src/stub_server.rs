@@ -0,0 +1,89 @@
+// HTTP server for the tests of the LLM backends, standing in for Ollama or an OpenAI API server.
+// Every path answers with its configured status and JSON body, other paths with 404.
+// The requests are recorded, so that tests can check what was sent.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+#[derive(Debug, Clone)]
+pub struct StubRequest {
+    pub path: String,
+    // Header names in lowercase
+    pub headers: HashMap<String, String>,
+    pub body: serde_json::Value,
+}
+
+pub struct StubServer {
+    // e.g. "http://127.0.0.1:40123"
+    pub url: String,
+    requests: Arc<Mutex<Vec<StubRequest>>>,
+}
+
+impl StubServer {
+    // Serve the (path, status, body) responses on a free local port until the test ends.
+    pub async fn start(responses: &[(&str, u16, &str)]) -> StubServer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let responses: HashMap<String, (u16, String)> = responses.iter()
+            .map(|(path, status, body)| (path.to_string(), (*status, body.to_string())))
+            .collect();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                answer(stream, &responses, &recorded).await;
+            }
+        });
+        StubServer { url, requests }
+    }
+
+    // All requests so far, in the order they were received.
+    pub fn requests(&self) -> Vec<StubRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+// Read one request from the connection, record and answer it. The connection is closed afterwards.
+async fn answer(mut stream: TcpStream, responses: &HashMap<String, (u16, String)>, requests: &Mutex<Vec<StubRequest>>) -> Option<()> {
+    let mut data = Vec::new();
+    let mut buffer = [0; 4096];
+    let header_end = loop {
+        let n = stream.read(&mut buffer).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        data.extend_from_slice(&buffer[..n]);
+        if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+    };
+    let head = String::from_utf8_lossy(&data[..header_end]).into_owned();
+    let mut lines = head.lines();
+    let path = lines.next()?.split_whitespace().nth(1)?.to_string();
+    let headers: HashMap<String, String> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect();
+    let length: usize = headers.get("content-length").and_then(|l| l.parse().ok()).unwrap_or(0);
+    while data.len() < header_end + length {
+        let n = stream.read(&mut buffer).await.ok()?;
+        if n == 0 {
+            break;
+        }
+        data.extend_from_slice(&buffer[..n]);
+    }
+    let body = serde_json::from_slice(&data[header_end..]).unwrap_or(serde_json::Value::Null);
+
+    requests.lock().unwrap().push(StubRequest { path: path.clone(), headers, body });
+
+    let (status, response) = responses.get(&path).cloned().unwrap_or((404, r#"{"error": "not found"}"#.to_string()));
+    let reply = format!(
+        "HTTP/1.1 {} Stub\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, response.len(), response
+    );
+    stream.write_all(reply.as_bytes()).await.ok()?;
+    stream.shutdown().await.ok()
+}
@@ -0,0 +1,213 @@
+use crate::error::DmsError;
+use crate::psql::Database;
+
+// At most this many tags are suggested from the buzzwords of a Document.
+const MAX_SUGGESTED_TAGS: usize = 5;
+// Longer buzzwords are rather sentences than tags.
+const MAX_TAG_WORDS: usize = 3;
+
+// Tags are compared case-insensitive, so they are stored in lowercase with single spaces.
+pub fn normalize(name: &str) -> String {
+    name.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+// "1." or "2)" in front of a buzzword.
+fn is_numbering(word: &str) -> bool {
+    let digits = word.trim_end_matches(['.', ')', ':']);
+    digits.len() < word.len() && !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+// Turn the free text buzzwords of the LLM into tag names.
+// The models answer in many shapes ("1. Rechnung 2. Auto", "Rechnung, Auto", "- Rechnung\n- Auto"),
+// so the text is split at list separators and numbering, and everything that looks like a sentence is dropped.
+pub fn suggestions(buzzwords: &str) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    for part in buzzwords.split([',', ';', '\n', '*', '|']) {
+        let mut groups: Vec<Vec<&str>> = vec![Vec::new()];
+        for word in part.split_whitespace() {
+            if is_numbering(word) || word == "-" {
+                groups.push(Vec::new());
+            } else if let Some(group) = groups.last_mut() {
+                group.push(word);
+            }
+        }
+        for group in groups {
+            let tag = normalize(group.join(" ").trim_matches(|c: char| !c.is_alphanumeric()));
+            if !tag.is_empty() && group.len() <= MAX_TAG_WORDS && !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+    }
+    tags.truncate(MAX_SUGGESTED_TAGS);
+    tags
+}
+
+// SQL expression listing the tags of the Document with the id in the column id_column as text[],
+// suggested tags marked with a trailing "?".
+pub fn tags_column(id_column: &str) -> String {
+    format!("ARRAY(SELECT t.name || CASE WHEN dt.suggested THEN '?' ELSE '' END
+        FROM dmslite.document_tags dt JOIN dmslite.tags t ON t.id = dt.tag_id
+        WHERE dt.id = {}
+        ORDER BY dt.suggested ASC, t.name ASC)", id_column)
+}
+
+// Id of the tag with the name.
+async fn tag_id(client: &tokio_postgres::Client, name: &str) -> Result<i32, DmsError> {
+    let row = client.query_opt("SELECT id FROM dmslite.tags WHERE name = $1", &[&name]).await?;
+    match row {
+        Some(row) => Ok(row.get(0)),
+        None => Err(DmsError::TagNotFound(name.to_string())),
+    }
+}
+
+// Add the tags to the Document with the <id>. Tags that do not exist yet are created,
+// suggested tags of the Document are confirmed.
+pub async fn add(db: &Database, id: i32, names: &[String]) -> Result<(), DmsError> {
+    let mut client = db.client().await?;
+    let transaction = client.transaction().await?;
+    if transaction.query_opt("SELECT 1 FROM dmslite.main_table WHERE id = $1", &[&id]).await?.is_none() {
+        return Err(DmsError::NotFound(id));
+    }
+    for name in names.iter().map(|n| normalize(n)).filter(|n| !n.is_empty()) {
+        transaction.execute("INSERT INTO dmslite.tags (name) VALUES ($1) ON CONFLICT (name) DO NOTHING", &[&name]).await?;
+        transaction.execute(
+            "INSERT INTO dmslite.document_tags (id, tag_id, suggested)
+            SELECT $1, id, false FROM dmslite.tags WHERE name = $2
+            ON CONFLICT (id, tag_id) DO UPDATE SET suggested = false",
+            &[&id, &name],
+        ).await?;
+        println!("Tagged document {} with {}", id, name);
+    }
+    transaction.commit().await?;
+    Ok(())
+}
+
+// Remove the tags from the Document with the <id>. Tags no Document uses anymore are deleted.
+pub async fn remove(db: &Database, id: i32, names: &[String]) -> Result<(), DmsError> {
+    let mut client = db.client().await?;
+    let transaction = client.transaction().await?;
+    for name in names.iter().map(|n| normalize(n)) {
+        let removed = transaction.execute(
+            "DELETE FROM dmslite.document_tags dt USING dmslite.tags t
+            WHERE dt.tag_id = t.id AND dt.id = $1 AND t.name = $2",
+            &[&id, &name],
+        ).await?;
+        if removed == 0 {
+            println!("Document {} is not tagged with {}", id, name);
+            continue;
+        }
+        transaction.execute(
+            "DELETE FROM dmslite.tags t
+            WHERE t.name = $1 AND NOT EXISTS (SELECT 1 FROM dmslite.document_tags dt WHERE dt.tag_id = t.id)",
+            &[&name],
+        ).await?;
+        println!("Removed {} from document {}", name, id);
+    }
+    transaction.commit().await?;
+    Ok(())
+}
+
+// Rename a tag. Fails if the new name is taken, merge the tags instead.
+pub async fn rename(db: &Database, old: &str, new: &str) -> Result<(), DmsError> {
+    let (old, new) = (normalize(old), normalize(new));
+    let client = db.client().await?;
+    let id = tag_id(&client, &old).await?;
+    if client.query_opt("SELECT 1 FROM dmslite.tags WHERE name = $1", &[&new]).await?.is_some() {
+        return Err(DmsError::TagExists(new));
+    }
+    client.execute("UPDATE dmslite.tags SET name = $2 WHERE id = $1", &[&id, &new]).await?;
+    println!("Renamed tag {} to {}", old, new);
+    Ok(())
+}
+
+// Move all Documents of the tag <from> to the tag <into> and delete <from>.
+// A Document keeps a suggested tag only if it was suggested for both.
+pub async fn merge(db: &Database, from: &str, into: &str) -> Result<(), DmsError> {
+    let (from, into) = (normalize(from), normalize(into));
+    let mut client = db.client().await?;
+    let from_id = tag_id(&client, &from).await?;
+    let into_id = tag_id(&client, &into).await?;
+    if from_id == into_id {
+        return Ok(());
+    }
+    let transaction = client.transaction().await?;
+    let moved = transaction.execute(
+        "INSERT INTO dmslite.document_tags (id, tag_id, suggested)
+        SELECT id, $2, suggested FROM dmslite.document_tags WHERE tag_id = $1
+        ON CONFLICT (id, tag_id) DO UPDATE SET suggested = dmslite.document_tags.suggested AND EXCLUDED.suggested",
+        &[&from_id, &into_id],
+    ).await?;
+    transaction.execute("DELETE FROM dmslite.tags WHERE id = $1", &[&from_id]).await?;
+    transaction.commit().await?;
+    println!("Merged tag {} into {} ({} documents)", from, into, moved);
+    Ok(())
+}
+
+// List all tags with the number of Documents using them.
+pub async fn list(db: &Database) -> Result<(), DmsError> {
+    let client = db.client().await?;
+    let rows = client.query(
+        "SELECT t.name, COUNT(dt.id) FILTER (WHERE NOT dt.suggested), COUNT(dt.id) FILTER (WHERE dt.suggested)
+        FROM dmslite.tags t LEFT JOIN dmslite.document_tags dt ON dt.tag_id = t.id
+        GROUP BY t.name
+        ORDER BY t.name",
+        &[],
+    ).await?;
+    if rows.is_empty() {
+        println!("No tags");
+    }
+    for row in rows {
+        let name: String = row.get(0);
+        let confirmed: i64 = row.get(1);
+        let suggested: i64 = row.get(2);
+        println!("{} ({} documents, {} suggested)", name, confirmed, suggested);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_case_and_spaces() {
+        assert_eq!(normalize("  Tax \t Return "), "tax return");
+        assert_eq!(normalize("KFZ-Versicherung"), "kfz-versicherung");
+        assert_eq!(normalize("Übersicht"), "übersicht");
+        assert_eq!(normalize(""), "");
+    }
+
+    #[test]
+    fn suggestions_comma_separated() {
+        assert_eq!(suggestions("Rechnung, Auto, rechnung, Werkstatt"), vec!["rechnung", "auto", "werkstatt"]);
+    }
+
+    #[test]
+    fn suggestions_numbered() {
+        assert_eq!(suggestions("1. Rechnung 2. Auto 3) Steuer Erklaerung"), vec!["rechnung", "auto", "steuer erklaerung"]);
+    }
+
+    #[test]
+    fn suggestions_markdown_list() {
+        assert_eq!(suggestions("- Rechnung\n- **Auto**\n* Werkstatt"), vec!["rechnung", "auto", "werkstatt"]);
+    }
+
+    #[test]
+    fn suggestions_drop_sentences() {
+        assert_eq!(suggestions("Here are the keywords for the document\nRechnung; Auto"), vec!["rechnung", "auto"]);
+    }
+
+    #[test]
+    fn suggestions_at_most_five() {
+        assert_eq!(suggestions("a1, b2, c3, d4, e5, f6, g7").len(), MAX_SUGGESTED_TAGS);
+        assert!(suggestions("").is_empty());
+    }
+
+    #[test]
+    fn numbering() {
+        assert!(is_numbering("1."));
+        assert!(is_numbering("12)"));
+        assert!(!is_numbering("2023"));
+        assert!(!is_numbering("."));
+    }
+}
@@ -0,0 +1,83 @@
+// Chunks end at the end of a sentence once they are filled to this part, otherwise at the last word that fits.
+const SENTENCE_BREAK: f32 = 0.75;
+
+// Tokens of a single word, estimated without the tokenizer of the model:
+// one per word plus one per four characters. Tokenizers split German words and OCR noise
+// into more pieces than English words, so this rather over- than underestimates.
+fn word_tokens(word: &str) -> usize {
+    1 + word.chars().count() / 4
+}
+
+// Estimated number of tokens of the text.
+pub fn estimate(text: &str) -> usize {
+    text.split_whitespace().map(word_tokens).sum()
+}
+
+// Split the text into chunks of at most max_tokens estimated tokens, preferably at the end of a sentence.
+// A single word longer than max_tokens becomes a chunk of its own.
+pub fn split(text: &str, max_tokens: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut chunk: Vec<&str> = Vec::new();
+    let mut tokens = 0;
+    for word in text.split_whitespace() {
+        let word_tokens = word_tokens(word);
+        if tokens + word_tokens > max_tokens && !chunk.is_empty() {
+            chunks.push(chunk.join(" "));
+            chunk.clear();
+            tokens = 0;
+        }
+        chunk.push(word);
+        tokens += word_tokens;
+        if word.ends_with(['.', '!', '?']) && tokens as f32 >= max_tokens as f32 * SENTENCE_BREAK {
+            chunks.push(chunk.join(" "));
+            chunk.clear();
+            tokens = 0;
+        }
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk.join(" "));
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate_words() {
+        assert_eq!(estimate(""), 0);
+        assert_eq!(estimate("a bb\n cccc"), 4);
+        assert_eq!(estimate("Kraftfahrzeugversicherung"), 7);
+    }
+
+    #[test]
+    fn split_short_text() {
+        assert_eq!(split("a  b\nc", 10), vec!["a b c"]);
+        assert!(split("", 10).is_empty());
+        assert!(split(" \n ", 10).is_empty());
+    }
+
+    #[test]
+    fn split_at_max_tokens() {
+        let text = "a ".repeat(25);
+        let chunks = split(&text, 10);
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|chunk| estimate(chunk) <= 10));
+        assert_eq!(chunks.join(" "), text.trim());
+    }
+
+    #[test]
+    fn split_at_sentence_end() {
+        assert_eq!(split("a a a a a a a a. b b b b", 10), vec!["a a a a a a a a.", "b b b b"]);
+        // A sentence ending early in the chunk does not end it
+        assert_eq!(split("a. b b b b", 10), vec!["a. b b b b"]);
+    }
+
+    #[test]
+    fn split_long_word() {
+        let word = "x".repeat(100);
+        let chunks = split(&format!("a {} b", word), 10);
+        assert_eq!(chunks, vec!["a".to_string(), word, "b".to_string()]);
+    }
+}
@@ -0,0 +1,107 @@
+use futures::StreamExt;
+use inotify::{Inotify, WatchMask};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::sleep;
+
+use crate::error::DmsError;
+use crate::ocr::DocumentKind;
+use crate::psql::Database;
+use crate::settings::Config;
+
+// Time between two size checks of a new file.
+const SETTLE_INTERVAL: Duration = Duration::from_secs(2);
+
+// Time until the consume dir is consumed again after a document failed without being at fault (e.g. Postgres is down).
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+// Some writers (e.g. network scanners) close and reopen a file while writing it.
+// Wait until the size did not change for one SETTLE_INTERVAL.
+// Returns false if the file vanished in the meantime.
+async fn wait_until_written(path: &Path) -> bool {
+    let mut last_size = None;
+    loop {
+        let size = match fs::metadata(path) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return false,
+        };
+        if last_size == Some(size) {
+            return true;
+        }
+        last_size = Some(size);
+        sleep(SETTLE_INTERVAL).await;
+    }
+}
+
+// Watch the consume dir with inotify and consume every PDF or image once it is completely written
+// (closed after writing or moved into the dir). Files already in the dir are consumed on start.
+// Documents that failed without being at fault stay in the consume dir and are retried every RETRY_INTERVAL.
+// SIGINT and SIGTERM stop the watcher after the current document, so it can run as a systemd service.
+pub async fn watch(config: &Config, db: &Database) -> Result<(), DmsError> {
+    let inotify = Inotify::init().map_err(DmsError::io("Cant init inotify"))?;
+    inotify.watches()
+        .add(&config.consume_path, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)
+        .map_err(DmsError::io(format!("Cant watch {}", config.consume_path.display())))?;
+    let mut events = inotify.into_event_stream([0; 4096])
+        .map_err(DmsError::io("Cant read inotify events"))?;
+
+    let mut sigterm = signal(SignalKind::terminate()).map_err(DmsError::io("Cant listen for SIGTERM"))?;
+    let mut sigint = signal(SignalKind::interrupt()).map_err(DmsError::io("Cant listen for SIGINT"))?;
+
+    println!("Watching {} for new documents.", config.consume_path.display());
+    let mut retry = false;
+    if let Err(e) = crate::consume(config, db).await {
+        eprintln!("{}", e);
+        retry = true;
+    }
+    // Files waiting for the retry, their events are ignored until then.
+    let mut waiting = HashSet::new();
+
+    loop {
+        let event = tokio::select! {
+            event = events.next() => event,
+            _ = sleep(RETRY_INTERVAL), if retry => {
+                retry = false;
+                waiting.clear();
+                if let Err(e) = crate::consume(config, db).await {
+                    eprintln!("{}", e);
+                    retry = true;
+                }
+                continue;
+            }
+            _ = sigterm.recv() => break,
+            _ = sigint.recv() => break,
+        };
+        let event = match event {
+            Some(Ok(event)) => event,
+            Some(Err(e)) => return Err(DmsError::io("inotify error")(e)),
+            None => break,
+        };
+
+        let Some(name) = event.name.and_then(|n| n.into_string().ok()) else {
+            continue;
+        };
+        // A file moved back after a failed consume raises MOVED_TO too, but it is in the failed folder
+        // or waiting for the retry by now.
+        let path = config.consume_path.join(&name);
+        if waiting.contains(&name) || DocumentKind::from_path(&path).is_none() || !wait_until_written(&path).await {
+            continue;
+        }
+        match crate::consume_file(config, db, name.clone()).await {
+            Ok(()) => println!("Consumed {}", name),
+            Err(e) => {
+                eprintln!("Failed to consume {}: {}", name, e);
+                if !e.is_caused_by_document() {
+                    waiting.insert(name);
+                    retry = true;
+                }
+            }
+        }
+    }
+
+    println!("Stopped watching.");
+    Ok(())
+}